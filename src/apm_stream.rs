@@ -0,0 +1,340 @@
+//! Read status lines, from lldb or from a debugger script of the user's own.
+//!
+//! Each line is a key, a colon, and an integer value, like `APM: 123`. The keys are:
+//!
+//! - `APM`: actions per minute.
+//! - `SUPPLY`: supply used.
+//! - `MINERALS`: minerals banked.
+//! - `CLOCK`: game time, in seconds.
+//!
+//! `STATE: gameover` says the game just ended, so vibration can stop without waiting for APM to go quiet.
+//! Other lines are ignored. `--drive-metric` chooses which value drives vibration.
+//! StarPlug's own lldb script only sends `APM` so far.
+
+use crate::mapper::Ewma;
+use crate::{Args, RollingApm};
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, watch};
+use tokio::time::sleep;
+use tracing::{info, warn};
+
+/// Wait this long before reopening a pipe that couldn't be opened.
+const REOPEN_WAIT: Duration = Duration::from_secs(1);
+
+/// Wait this long before accepting again after a failed accept,
+/// so an error that doesn't go away, like running out of file descriptors, doesn't spin.
+const ACCEPT_RETRY_WAIT: Duration = Duration::from_millis(100);
+
+/// Which value from the status lines drives vibration.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DriveMetric {
+    Apm,
+    Supply,
+    Minerals,
+    Clock,
+}
+
+/// What the status lines tell the loop that sends vibration commands.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    /// New value of the driving metric. Usually APM, hence the name.
+    Apm(i32),
+    /// The game ended.
+    GameOver,
+}
+
+/// Everything the status lines have said about the game so far.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct GameStats {
+    pub apm: Option<i32>,
+    pub supply: Option<i32>,
+    pub minerals: Option<i32>,
+    pub clock: Option<i32>,
+}
+
+impl GameStats {
+    /// Update from one status line. Returns the metric the line was about, if it was a status line.
+    fn apply(&mut self, line: &str) -> Option<DriveMetric> {
+        let (key, value) = line.split_once(':')?;
+        let value = value.trim().parse::<i32>().ok()?;
+        let (metric, field) = match key {
+            "APM" => (DriveMetric::Apm, &mut self.apm),
+            "SUPPLY" => (DriveMetric::Supply, &mut self.supply),
+            "MINERALS" => (DriveMetric::Minerals, &mut self.minerals),
+            "CLOCK" => (DriveMetric::Clock, &mut self.clock),
+            _ => return None,
+        };
+        *field = Some(value);
+        Some(metric)
+    }
+
+    pub fn get(&self, metric: DriveMetric) -> Option<i32> {
+        match metric {
+            DriveMetric::Apm => self.apm,
+            DriveMetric::Supply => self.supply,
+            DriveMetric::Minerals => self.minerals,
+            DriveMetric::Clock => self.clock,
+        }
+    }
+}
+
+/// Turns status lines into values of the driving metric, averaged, smoothed, and with repeats dropped.
+pub struct StatusParser {
+    metric: DriveMetric,
+    stats: GameStats,
+    window: Option<RollingApm>,
+    ewma: Ewma,
+    prev_value: i32,
+    /// Gets every raw value of the driving metric, for `--log-csv-every-sample`.
+    samples: Option<mpsc::Sender<i32>>,
+    warned_about_dropped_samples: bool,
+}
+
+impl StatusParser {
+    /// Set up `--drive-metric`, `--apm-window-seconds`, and `--smoothing-alpha`,
+    /// and send raw values to `samples` if given.
+    pub fn new(args: &Args, samples: Option<mpsc::Sender<i32>>) -> Self {
+        Self {
+            metric: args.drive_metric,
+            stats: GameStats::default(),
+            window: args
+                .apm_window_seconds
+                .map(|seconds| RollingApm::new(Duration::from_secs(seconds))),
+            ewma: Ewma::new(args.smoothing_alpha),
+            prev_value: 0,
+            samples,
+            warned_about_dropped_samples: false,
+        }
+    }
+
+    /// Update from a status line, and get the driving metric's new value if this line changed it,
+    /// or whether the game ended.
+    pub fn parse(&mut self, line: &str) -> Option<Status> {
+        if let Some(("STATE", state)) = line.split_once(':') {
+            if state.trim() != "gameover" {
+                return None;
+            }
+            self.stats = GameStats::default();
+            self.prev_value = 0;
+            return Some(Status::GameOver);
+        }
+        if self.stats.apply(line)? != self.metric {
+            return None;
+        }
+        let mut value = self.stats.get(self.metric)?;
+        if let Some(samples) = &self.samples {
+            // Never wait for the sample log, so it can't hold up vibration.
+            if let Err(TrySendError::Full(_)) = samples.try_send(value) {
+                if !self.warned_about_dropped_samples {
+                    warn!("The CSV log is falling behind, so some samples won't be in it.");
+                    self.warned_about_dropped_samples = true;
+                }
+            }
+        }
+        if let Some(window) = &mut self.window {
+            // Until there's a full window of samples, the raw value is the best we have.
+            if let Some(average) = window.push(value) {
+                value = average.round() as i32;
+            }
+        }
+        let value = self.ewma.update(value);
+        if value == self.prev_value {
+            return None;
+        }
+        self.prev_value = value;
+        Some(Status::Apm(value))
+    }
+}
+
+/// Send APM from status lines until they run out.
+/// Returns false if nobody is listening for APM anymore.
+async fn forward_apm(
+    reader: impl AsyncBufRead + Unpin,
+    parser: &mut StatusParser,
+    apm_tx: &watch::Sender<Status>,
+) -> bool {
+    let mut lines = reader.lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if let Some(status) = parser.parse(&line) {
+            if apm_tx.send(status).is_err() {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+/// Read status lines from a named pipe, reopening it whenever the writer closes it.
+pub fn watch_pipe(path: PathBuf, mut parser: StatusParser) -> watch::Receiver<Status> {
+    let (apm_tx, apm_rx) = watch::channel(Status::Apm(0));
+    tokio::spawn(async move {
+        let path_display = path.display();
+        loop {
+            // Opening a pipe for reading waits until something opens it for writing.
+            let file = match tokio::fs::File::open(&path).await {
+                Ok(file) => file,
+                Err(e) => {
+                    warn!("Couldn't open APM pipe {path_display}: {e}");
+                    sleep(REOPEN_WAIT).await;
+                    continue;
+                }
+            };
+            info!("Reading APM from {path_display}.");
+            if !forward_apm(BufReader::new(file), &mut parser, &apm_tx).await {
+                return;
+            }
+            info!("APM pipe {path_display} was closed. Waiting for a new writer…");
+        }
+    });
+    apm_rx
+}
+
+/// Listen for status lines on a TCP socket, from one connection at a time.
+pub async fn watch_tcp(addr: String, mut parser: StatusParser) -> Result<watch::Receiver<Status>> {
+    let listener = TcpListener::bind(&addr)
+        .await
+        .map_err(|e| anyhow!(e).context(format!("Couldn't listen for APM on {addr}")))?;
+    info!("Listening for APM on {addr}.");
+    let (apm_tx, apm_rx) = watch::channel(Status::Apm(0));
+    tokio::spawn(async move {
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("Couldn't accept APM connection: {e}");
+                    sleep(ACCEPT_RETRY_WAIT).await;
+                    continue;
+                }
+            };
+            info!("Reading APM from {peer}.");
+            if !forward_apm(BufReader::new(stream), &mut parser, &apm_tx).await {
+                return;
+            }
+            info!("APM connection from {peer} closed. Waiting for a new one…");
+        }
+    });
+    Ok(apm_rx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn parser(args: &[&str]) -> StatusParser {
+        let args = Args::parse_from(std::iter::once("starplug").chain(args.iter().copied()));
+        StatusParser::new(&args, None)
+    }
+
+    fn parse_all(parser: &mut StatusParser, lines: &[&str]) -> Vec<Status> {
+        lines.iter().filter_map(|line| parser.parse(line)).collect()
+    }
+
+    #[test]
+    fn game_stats_apply_reads_each_key() {
+        let mut stats = GameStats::default();
+        assert_eq!(stats.apply("APM: 120"), Some(DriveMetric::Apm));
+        assert_eq!(stats.apply("SUPPLY:34"), Some(DriveMetric::Supply));
+        assert_eq!(stats.apply("MINERALS: 500 "), Some(DriveMetric::Minerals));
+        assert_eq!(stats.apply("CLOCK: 61"), Some(DriveMetric::Clock));
+        assert_eq!(
+            stats,
+            GameStats {
+                apm: Some(120),
+                supply: Some(34),
+                minerals: Some(500),
+                clock: Some(61),
+            }
+        );
+    }
+
+    #[test]
+    fn game_stats_apply_ignores_other_lines() {
+        let mut stats = GameStats::default();
+        assert_eq!(stats.apply("ATTACHED: 1234"), None);
+        assert_eq!(stats.apply("APM: lots"), None);
+        assert_eq!(stats.apply("APM 120"), None);
+        assert_eq!(stats.apply(""), None);
+        assert_eq!(stats, GameStats::default());
+    }
+
+    #[test]
+    fn parse_drops_repeats_and_other_lines() {
+        let mut parser = parser(&[]);
+        assert_eq!(
+            parse_all(
+                &mut parser,
+                &[
+                    "APM: 60",
+                    "APM: 60",
+                    "hello",
+                    "SUPPLY: 10",
+                    "APM: 70",
+                    "APM: 70"
+                ]
+            ),
+            vec![Status::Apm(60), Status::Apm(70)]
+        );
+    }
+
+    #[test]
+    fn parse_reports_game_over_and_starts_over() {
+        let mut parser = parser(&[]);
+        assert_eq!(
+            parse_all(
+                &mut parser,
+                &["APM: 60", "STATE: paused", "STATE: gameover", "APM: 60"]
+            ),
+            vec![Status::Apm(60), Status::GameOver, Status::Apm(60)]
+        );
+    }
+
+    #[test]
+    fn parse_follows_the_drive_metric() {
+        let mut parser = parser(&["--drive-metric", "supply"]);
+        assert_eq!(
+            parse_all(
+                &mut parser,
+                &["APM: 60", "SUPPLY: 12", "APM: 80", "SUPPLY: 14"]
+            ),
+            vec![Status::Apm(12), Status::Apm(14)]
+        );
+    }
+
+    #[test]
+    fn parse_smooths_values() {
+        let mut parser = parser(&["--smoothing-alpha", "0.5"]);
+        assert_eq!(
+            parse_all(&mut parser, &["APM: 100", "APM: 0", "APM: 0"]),
+            vec![Status::Apm(100), Status::Apm(50), Status::Apm(25)]
+        );
+    }
+
+    #[test]
+    fn parse_passes_raw_values_until_the_window_fills() {
+        let mut parser = parser(&["--apm-window-seconds", "60"]);
+        assert_eq!(
+            parse_all(&mut parser, &["APM: 100", "APM: 200"]),
+            vec![Status::Apm(100), Status::Apm(200)]
+        );
+    }
+
+    #[test]
+    fn parse_sends_every_raw_sample() {
+        let args = Args::parse_from(["starplug", "--smoothing-alpha", "0.5"]);
+        let (samples_tx, mut samples_rx) = mpsc::channel(2);
+        let mut parser = StatusParser::new(&args, Some(samples_tx));
+        parse_all(&mut parser, &["APM: 100", "APM: 100", "APM: 0"]);
+        assert_eq!(samples_rx.try_recv(), Ok(100));
+        assert_eq!(samples_rx.try_recv(), Ok(100));
+        // The channel was full, so the third sample was dropped instead of waited for.
+        assert!(samples_rx.try_recv().is_err());
+        assert!(parser.warned_about_dropped_samples);
+    }
+}
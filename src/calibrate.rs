@@ -0,0 +1,76 @@
+//! Suggest a min and max APM from a stretch of play, for new players who don't know what to pick.
+
+use crate::apm_stream::Status;
+use crate::mapper::suggest_apm_range;
+use crate::{connect_to_starcraft, Args, ChildShutdown};
+use anyhow::{bail, Result};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{watch, Mutex};
+use tokio::time::timeout;
+use tracing::info;
+
+/// How often to say how much longer calibration will take.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Record APM for `--calibrate-duration` of play, then print the settings to use.
+/// Never sends anything to a device.
+pub async fn calibrate(
+    args: &Args,
+    external_apm: Option<watch::Receiver<Status>>,
+    running_lldb: Arc<Mutex<Option<ChildShutdown>>>,
+) -> Result<()> {
+    let mut apm_rx = match external_apm {
+        Some(apm_rx) => apm_rx,
+        None => match connect_to_starcraft(args, running_lldb, None).await? {
+            Some(apm_rx) => apm_rx,
+            None => bail!("Couldn't get APM from StarCraft."),
+        },
+    };
+    let duration = args.calibrate_duration;
+    info!(
+        "Calibrating: play normally for {duration}. Recording starts with your first action.",
+        duration = humantime::format_duration(duration)
+    );
+
+    let mut samples = vec![];
+    let mut started: Option<Instant> = None;
+    let mut last_progress = Instant::now();
+    loop {
+        let remaining = started.map_or(duration, |started| {
+            duration.saturating_sub(started.elapsed())
+        });
+        if remaining.is_zero() {
+            break;
+        }
+        match timeout(remaining.min(PROGRESS_INTERVAL), apm_rx.changed()).await {
+            Ok(Ok(())) => {
+                // Zero is what StarCraft reports outside of games.
+                if let Status::Apm(apm @ 1..) = *apm_rx.borrow_and_update() {
+                    started.get_or_insert_with(Instant::now);
+                    samples.push(apm);
+                }
+            }
+            Ok(Err(_)) => bail!("APM stopped before calibration finished."),
+            Err(_) => {}
+        }
+        if started.is_some() && last_progress.elapsed() >= PROGRESS_INTERVAL {
+            last_progress = Instant::now();
+            info!(
+                "Calibrating: {count} samples so far, {remaining} to go.",
+                count = samples.len(),
+                remaining = humantime::format_duration(Duration::from_secs(remaining.as_secs()))
+            );
+        }
+    }
+
+    let Some((min_apm, max_apm)) = suggest_apm_range(&samples) else {
+        bail!("No APM was recorded during calibration.");
+    };
+    info!(
+        "Calibration finished with {count} samples.",
+        count = samples.len()
+    );
+    println!("Suggested settings for next time: --min-apm {min_apm} --max-apm {max_apm}");
+    Ok(())
+}
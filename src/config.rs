@@ -0,0 +1,590 @@
+//! Read arguments from a TOML config file.
+//!
+//! Keys are argument names, like `min_apm = 80` or `compress-knee = 0.8`.
+//! Arguments given on the command line override the same settings in the file.
+//!
+//! An `[actuators.<type>]` table, like `[actuators.rotate]`, gives one kind of actuator its own
+//! `min_apm`, `max_apm`, and `curve`, and can turn it on or off with `enabled`.
+//! Actuator types with their own thresholds follow APM directly, without ramping, fading out, or soft starting.
+//! `--actuators` on the command line overrides `enabled`.
+//!
+//! A `[devices."<name>"]` table gives settings for the device with that name,
+//! and `[devices."/<regex>/"]` for every device whose name matches the regular expression.
+//! An entry can set `actuators`, `actuator_map`, and `min_command_interval`, which replace the global flags
+//! for its devices, and `scale`, which replaces a remembered scale but not `--device-scale`.
+//! `min_level` and `max_level` squeeze the device's range, so any vibration at all is at least `min_level`,
+//! and `steps` rounds levels to that many steps, for devices with only a few speeds.
+//! A device with no entry uses the global settings. An entry for its exact name wins over regexes,
+//! and regexes are tried in the order they sort in.
+
+use crate::mapper::{ActuatorMapping, ActuatorThresholds, Curve};
+use crate::{Actuator, Args};
+use anyhow::{anyhow, bail, Context, Result};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, ValueEnum};
+use regex::Regex;
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Where the config file is read from when `--config` isn't given.
+/// On macOS, this is `~/Library/Application Support/StarPlug/config.toml`.
+pub fn default_config_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("StarPlug").join("config.toml"))
+}
+
+/// Where each argument's value came from, for error messages.
+pub struct Sources {
+    matches: ArgMatches,
+    config_path: Option<PathBuf>,
+    from_config: HashSet<String>,
+}
+
+impl Sources {
+    /// Describe where an argument came from, like "on the command line".
+    pub fn of(&self, id: &str) -> String {
+        if let Some(path) = &self.config_path {
+            if self.from_config.contains(id) {
+                return format!("in {path}", path = path.display());
+            }
+        }
+        match self.matches.value_source(id) {
+            Some(ValueSource::DefaultValue) => "by default".to_owned(),
+            Some(ValueSource::EnvVariable) => "by an environment variable".to_owned(),
+            _ => "on the command line".to_owned(),
+        }
+    }
+}
+
+/// Parse command-line arguments, filling in anything they don't set from the config file.
+pub fn parse_args() -> Result<(Args, Sources)> {
+    parse_args_from(std::env::args_os().collect())
+}
+
+/// [`parse_args`] for these command-line arguments, starting with the program name.
+fn parse_args_from(cli_args: Vec<OsString>) -> Result<(Args, Sources)> {
+    let cli_matches = Args::command().get_matches_from(&cli_args);
+
+    let config_path = match cli_matches.get_one::<PathBuf>("config") {
+        Some(path) => Some(path.clone()),
+        None => default_config_path().filter(|path| path.exists()),
+    };
+    let Some(config_path) = config_path else {
+        return Ok((
+            Args::from_arg_matches(&cli_matches)?,
+            Sources {
+                matches: cli_matches,
+                config_path: None,
+                from_config: HashSet::new(),
+            },
+        ));
+    };
+
+    let (config_args, from_config, tables) = read_config(&config_path, &cli_matches)?;
+    // Settings from the file go first so that a subcommand on the command line still comes last.
+    let mut merged_args = cli_args[..1].to_vec();
+    merged_args.extend(config_args);
+    merged_args.extend_from_slice(&cli_args[1..]);
+    let matches = Args::command()
+        .try_get_matches_from(merged_args)
+        .map_err(|e| {
+            anyhow!(e.to_string()).context(format!(
+                "Invalid setting in {path}",
+                path = config_path.display()
+            ))
+        })?;
+
+    let mut args = Args::from_arg_matches(&matches)?;
+    tables.apply(&mut args, &cli_matches);
+    Ok((
+        args,
+        Sources {
+            matches,
+            config_path: Some(config_path),
+            from_config,
+        },
+    ))
+}
+
+/// Settings from the config file's tables, which don't stand in for command-line arguments.
+#[derive(Default)]
+struct Tables {
+    /// From `[actuators.<type>]`: whether to drive the type, and its own thresholds.
+    actuators: Vec<(Actuator, Option<bool>, ActuatorThresholds)>,
+    /// From `[devices]`.
+    devices: Vec<DeviceSettings>,
+}
+
+impl Tables {
+    fn apply(self, args: &mut Args, cli_matches: &ArgMatches) {
+        let actuators_on_command_line =
+            cli_matches.value_source("actuators") == Some(ValueSource::CommandLine);
+        for (actuator, enabled, thresholds) in self.actuators {
+            match enabled {
+                _ if actuators_on_command_line => {}
+                Some(true) if !args.actuators.contains(&actuator) => args.actuators.push(actuator),
+                Some(false) => args.actuators.retain(|a| *a != actuator),
+                _ => {}
+            }
+            if !thresholds.is_empty() {
+                args.actuator_thresholds.push((actuator, thresholds));
+            }
+        }
+        args.device_settings = self.devices;
+    }
+}
+
+/// Which devices a `[devices]` entry is for.
+#[derive(Clone, Debug)]
+pub enum DeviceMatcher {
+    Name(String),
+    Regex(Regex),
+}
+
+/// Settings from one `[devices]` entry. Anything not given falls back to the global setting.
+#[derive(Clone, Debug)]
+pub struct DeviceSettings {
+    pub matcher: DeviceMatcher,
+    pub actuators: Option<Vec<Actuator>>,
+    pub actuator_map: Option<Vec<ActuatorMapping>>,
+    pub min_command_interval: Option<Duration>,
+    pub scale: Option<f64>,
+    pub min_level: Option<f64>,
+    pub max_level: Option<f64>,
+    pub steps: Option<u32>,
+}
+
+impl DeviceSettings {
+    /// Entry for this device: the one for its exact name, or else the first regex that matches it.
+    pub fn find<'a>(entries: &'a [DeviceSettings], name: &str) -> Option<&'a DeviceSettings> {
+        entries
+            .iter()
+            .find(|entry| matches!(&entry.matcher, DeviceMatcher::Name(n) if n == name))
+            .or_else(|| {
+                entries.iter().find(
+                    |entry| matches!(&entry.matcher, DeviceMatcher::Regex(regex) if regex.is_match(name)),
+                )
+            })
+    }
+
+    /// Squeeze a level into `min_level` to `max_level`, keeping 0 at 0, then round it to `steps`.
+    pub fn level(&self, level: f64) -> f64 {
+        if level <= 0.0 {
+            return 0.0;
+        }
+        let (min, max) = (self.min_level.unwrap_or(0.0), self.max_level.unwrap_or(1.0));
+        let level = min + level * (max - min);
+        match self.steps {
+            Some(steps) => (level * steps as f64).round() / steps as f64,
+            None => level,
+        }
+    }
+}
+
+/// Read the `[devices]` table: one table for each device name or `/regex/`.
+fn read_device_tables(
+    path_display: &std::path::Display,
+    value: toml::Value,
+) -> Result<Vec<DeviceSettings>> {
+    let toml::Value::Table(devices) = value else {
+        bail!("devices in {path_display} must be a table, like [devices.\"Lovense Hush\"]");
+    };
+    let mut entries = vec![];
+    for (key, settings) in devices {
+        let matcher = match key
+            .strip_prefix('/')
+            .and_then(|pattern| pattern.strip_suffix('/'))
+        {
+            Some(pattern) => DeviceMatcher::Regex(
+                Regex::new(pattern)
+                    .with_context(|| format!("Bad regex [devices.{key:?}] in {path_display}"))?,
+            ),
+            None => DeviceMatcher::Name(key.clone()),
+        };
+        let toml::Value::Table(settings) = settings else {
+            bail!("devices.{key:?} in {path_display} must be a table");
+        };
+        let mut entry = DeviceSettings {
+            matcher,
+            actuators: None,
+            actuator_map: None,
+            min_command_interval: None,
+            scale: None,
+            min_level: None,
+            max_level: None,
+            steps: None,
+        };
+        for (setting, value) in settings {
+            let bad_value = || anyhow!("Bad {setting} in [devices.{key:?}] in {path_display}");
+            let level = |value: &toml::Value| match value {
+                toml::Value::Float(level) if (0.0..=1.0).contains(level) => Ok(*level),
+                toml::Value::Integer(level @ 0..=1) => Ok(*level as f64),
+                _ => Err(bad_value()),
+            };
+            let strings = |value: &toml::Value| -> Result<Vec<String>> {
+                let values = match value {
+                    toml::Value::Array(values) => values.clone(),
+                    value => vec![value.clone()],
+                };
+                values
+                    .into_iter()
+                    .map(|value| match value {
+                        toml::Value::String(value) => Ok(value),
+                        _ => Err(bad_value()),
+                    })
+                    .collect()
+            };
+            match setting.replace('-', "_").as_str() {
+                "actuators" => {
+                    entry.actuators = Some(
+                        strings(&value)?
+                            .iter()
+                            .map(|actuator| Actuator::from_str(actuator, true))
+                            .collect::<Result<_, _>>()
+                            .map_err(|_| bad_value())?,
+                    )
+                }
+                "actuator_map" => {
+                    entry.actuator_map = Some(
+                        strings(&value)?
+                            .iter()
+                            .map(|mapping| mapping.parse())
+                            .collect::<Result<_, _>>()
+                            .map_err(|e| bad_value().context(e))?,
+                    )
+                }
+                "min_command_interval" => {
+                    let toml::Value::String(interval) = &value else {
+                        return Err(bad_value());
+                    };
+                    entry.min_command_interval = Some(
+                        humantime::parse_duration(interval).map_err(|e| bad_value().context(e))?,
+                    )
+                }
+                "scale" => {
+                    entry.scale = Some(match value {
+                        toml::Value::Float(scale) if scale >= 0.0 => scale,
+                        toml::Value::Integer(scale) if scale >= 0 => scale as f64,
+                        _ => return Err(bad_value()),
+                    })
+                }
+                "min_level" => entry.min_level = Some(level(&value)?),
+                "max_level" => entry.max_level = Some(level(&value)?),
+                "steps" => {
+                    entry.steps = Some(match value {
+                        toml::Value::Integer(steps) if steps > 0 => {
+                            u32::try_from(steps).map_err(|_| bad_value())?
+                        }
+                        _ => return Err(bad_value()),
+                    })
+                }
+                _ => bail!("Unknown setting {setting} in [devices.{key:?}] in {path_display}"),
+            }
+        }
+        if entry.min_level.unwrap_or(0.0) > entry.max_level.unwrap_or(1.0) {
+            bail!("min_level can't be above max_level in [devices.{key:?}] in {path_display}");
+        }
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// Read the `[actuators]` table: one table for each actuator type.
+fn read_actuator_tables(
+    path_display: &std::path::Display,
+    value: toml::Value,
+) -> Result<Vec<(Actuator, Option<bool>, ActuatorThresholds)>> {
+    let toml::Value::Table(types) = value else {
+        bail!("actuators in {path_display} must be a table, like [actuators.rotate]");
+    };
+    let mut actuators = vec![];
+    for (name, settings) in types {
+        let actuator = Actuator::from_str(&name, true)
+            .map_err(|_| anyhow!("Unknown actuator type [actuators.{name}] in {path_display}"))?;
+        let toml::Value::Table(settings) = settings else {
+            bail!("actuators.{name} in {path_display} must be a table");
+        };
+        let mut enabled = None;
+        let mut thresholds = ActuatorThresholds::default();
+        for (key, value) in settings {
+            let bad_value = || anyhow!("Bad {key} in [actuators.{name}] in {path_display}");
+            match (key.replace('-', "_").as_str(), &value) {
+                ("enabled", toml::Value::Boolean(flag)) => enabled = Some(*flag),
+                ("min_apm", toml::Value::Integer(apm)) => {
+                    thresholds.min_apm = Some(i32::try_from(*apm).map_err(|_| bad_value())?)
+                }
+                ("max_apm", toml::Value::Integer(apm)) => {
+                    thresholds.max_apm = Some(i32::try_from(*apm).map_err(|_| bad_value())?)
+                }
+                ("curve", toml::Value::String(curve)) => {
+                    thresholds.curve = Some(curve.parse::<Curve>().map_err(|e| {
+                        anyhow!(e)
+                            .context(format!("Bad curve in [actuators.{name}] in {path_display}"))
+                    })?)
+                }
+                ("enabled" | "min_apm" | "max_apm" | "curve", _) => return Err(bad_value()),
+                _ => bail!("Unknown setting {key} in [actuators.{name}] in {path_display}"),
+            }
+        }
+        if actuator == Actuator::Linear && !thresholds.is_empty() {
+            bail!("[actuators.linear] in {path_display} can only set enabled: strokers always follow the shared level");
+        }
+        actuators.push((actuator, enabled, thresholds));
+    }
+    Ok(actuators)
+}
+
+/// Turn a config file into arguments, skipping anything already given on the command line.
+/// Also returns the IDs of the arguments that came from the file, and the file's tables.
+fn read_config(
+    path: &Path,
+    cli_matches: &ArgMatches,
+) -> Result<(Vec<OsString>, HashSet<String>, Tables)> {
+    let path_display = path.display();
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Couldn't read config file {path_display}"))?;
+    let table: toml::Table = text
+        .parse()
+        .with_context(|| format!("Couldn't parse config file {path_display}"))?;
+
+    let command = Args::command();
+    let mut config_args = vec![];
+    let mut from_config = HashSet::new();
+    let mut tables = Tables::default();
+    for (key, value) in table {
+        if key == "actuators" {
+            tables.actuators = read_actuator_tables(&path_display, value)?;
+            continue;
+        }
+        if key == "devices" {
+            tables.devices = read_device_tables(&path_display, value)?;
+            continue;
+        }
+        let id = key.replace('-', "_");
+        let Some(arg) = command
+            .get_arguments()
+            .find(|arg| arg.get_id().as_str() == id)
+        else {
+            bail!("Unknown setting {key} in {path_display}");
+        };
+        let Some(long) = arg.get_long().filter(|_| id != "config") else {
+            bail!("{key} can't be set in a config file");
+        };
+        if cli_matches.value_source(&id) == Some(ValueSource::CommandLine) {
+            continue;
+        }
+
+        let values = match value {
+            toml::Value::Array(values) => values,
+            value => vec![value],
+        };
+        for value in values {
+            let value = match value {
+                toml::Value::Boolean(flag) if !arg.get_action().takes_values() => {
+                    if flag {
+                        config_args.push(format!("--{long}").into());
+                    }
+                    continue;
+                }
+                toml::Value::String(value) => value,
+                toml::Value::Integer(value) => value.to_string(),
+                toml::Value::Float(value) => value.to_string(),
+                toml::Value::Boolean(value) => value.to_string(),
+                _ => bail!("{key} in {path_display} must be a string, number, or boolean"),
+            };
+            // `=` keeps negative numbers from looking like flags.
+            config_args.push(format!("--{long}={value}").into());
+        }
+        from_config.insert(id);
+    }
+    Ok((config_args, from_config, tables))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use std::io::Write;
+
+    /// Parse these arguments with a config file holding `config`.
+    fn parse_with_config(config: &str, args: &[&str]) -> (Args, Sources) {
+        let mut file = tempfile::Builder::new().suffix(".toml").tempfile().unwrap();
+        file.write_all(config.as_bytes()).unwrap();
+        let mut cli_args: Vec<OsString> = vec!["starplug".into(), "--config".into()];
+        cli_args.push(file.path().into());
+        cli_args.extend(args.iter().map(OsString::from));
+        parse_args_from(cli_args).unwrap()
+    }
+
+    #[test]
+    fn config_file_overrides_defaults() {
+        let (args, sources) = parse_with_config("min_apm = 80\ncompress-knee = 0.8", &[]);
+        assert_eq!(args.min_apm, 80);
+        assert_eq!(args.compress_knee, Some(0.8));
+        assert!(sources.of("min_apm").starts_with("in "));
+        assert_eq!(sources.of("max_apm"), "by default");
+    }
+
+    #[test]
+    fn command_line_overrides_config_file() {
+        let (args, sources) =
+            parse_with_config("min_apm = 80\nmax_apm = 200", &["--min-apm", "50"]);
+        assert_eq!(args.min_apm, 50);
+        assert_eq!(args.max_apm, 200);
+        assert_eq!(sources.of("min_apm"), "on the command line");
+    }
+
+    #[test]
+    fn command_line_set_to_the_default_still_overrides_config_file() {
+        let default_args = Args::parse_from(["starplug"]);
+        let default_min_apm = default_args.min_apm.to_string();
+        let (args, _) = parse_with_config("min_apm = 80", &["--min-apm", &default_min_apm]);
+        assert_eq!(args.min_apm, default_args.min_apm);
+    }
+
+    #[test]
+    fn config_file_sets_flags_and_lists() {
+        let (args, _) = parse_with_config(
+            "dry_run = true\nonce = false\ndevice_scale = [\"Lovense Hush=0.6\", \"Lovense Lush=0.8\"]",
+            &[],
+        );
+        assert!(args.dry_run);
+        assert!(!args.once);
+        assert_eq!(args.device_scale.len(), 2);
+    }
+
+    #[test]
+    fn config_file_sets_actuator_tables() {
+        let (args, _) = parse_with_config(
+            "[actuators.rotate]\nenabled = true\nmin_apm = 90\ncurve = \"quadratic\"\n[actuators.vibrate]\nenabled = false",
+            &[],
+        );
+        assert_eq!(args.actuators, vec![Actuator::Rotate]);
+        assert_eq!(
+            args.actuator_thresholds,
+            vec![(
+                Actuator::Rotate,
+                ActuatorThresholds {
+                    min_apm: Some(90),
+                    max_apm: None,
+                    curve: Some(Curve::Quadratic),
+                }
+            )]
+        );
+
+        // --actuators on the command line wins over enabled.
+        let (args, _) = parse_with_config(
+            "[actuators.rotate]\nenabled = true",
+            &["--actuators", "vibrate"],
+        );
+        assert_eq!(args.actuators, vec![Actuator::Vibrate]);
+    }
+
+    const DEVICES: &str = r#"
+[devices."Lovense Hush"]
+actuators = ["vibrate", "rotate"]
+actuator_map = ["0=fixed:0.3"]
+min_command_interval = "100ms"
+scale = 0.5
+
+[devices."/^Lovense/"]
+min_level = 0.2
+max_level = 0.8
+steps = 3
+"#;
+
+    #[test]
+    fn config_file_sets_device_tables() {
+        let (args, _) = parse_with_config(DEVICES, &[]);
+        assert_eq!(args.device_settings.len(), 2);
+
+        let hush = args.device_entry("Lovense Hush").unwrap();
+        assert!(matches!(&hush.matcher, DeviceMatcher::Name(name) if name == "Lovense Hush"));
+        assert_eq!(
+            hush.actuators,
+            Some(vec![Actuator::Vibrate, Actuator::Rotate])
+        );
+        assert_eq!(
+            hush.actuator_map,
+            Some(vec!["0=fixed:0.3".parse().unwrap()])
+        );
+        assert_eq!(hush.min_command_interval, Some(Duration::from_millis(100)));
+        assert_eq!(hush.scale, Some(0.5));
+        assert_eq!(hush.steps, None);
+
+        let lush = args.device_entry("Lovense Lush").unwrap();
+        assert!(matches!(&lush.matcher, DeviceMatcher::Regex(_)));
+        assert_eq!((lush.min_level, lush.max_level), (Some(0.2), Some(0.8)));
+        assert_eq!(lush.steps, Some(3));
+        // 0.5 squeezes to 0.5 and rounds to 2/3. Off stays off.
+        assert!((lush.level(0.5) - 2.0 / 3.0).abs() < 1e-12);
+        assert_eq!(lush.level(0.0), 0.0);
+    }
+
+    #[test]
+    fn devices_without_entries_use_global_settings() {
+        let (args, _) = parse_with_config(
+            DEVICES,
+            &[
+                "--actuators",
+                "constrict",
+                "--actuator-map",
+                "1=follow",
+                "--min-command-interval",
+                "50ms",
+                "--device-scale",
+                "Lovense Hush=0.9",
+            ],
+        );
+        assert_eq!(
+            args.device_actuators("Lovense Hush"),
+            [Actuator::Vibrate, Actuator::Rotate]
+        );
+        assert_eq!(args.device_actuators("Lovense Lush"), [Actuator::Constrict]);
+        assert_eq!(args.device_actuators("We-Vibe Sync"), [Actuator::Constrict]);
+        assert_eq!(
+            args.device_actuator_map("We-Vibe Sync"),
+            ["1=follow".parse().unwrap()]
+        );
+        assert!(args.device_entry("We-Vibe Sync").is_none());
+        assert_eq!(args.min_command_interval, Some(Duration::from_millis(50)));
+        // --device-scale wins over an entry's scale.
+        assert_eq!(args.device_scale("Lovense Hush"), 0.9);
+        assert_eq!(args.device_scale("Lovense Lush"), 1.0);
+    }
+
+    #[test]
+    fn bad_config_files_are_errors() {
+        let mut file = tempfile::Builder::new().suffix(".toml").tempfile().unwrap();
+        file.write_all(b"no_such_setting = 1").unwrap();
+        let cli_args: Vec<OsString> =
+            vec!["starplug".into(), "--config".into(), file.path().into()];
+        assert!(parse_args_from(cli_args).is_err());
+
+        for config in [
+            "actuators = 1",
+            "[actuators.wiggle]\nenabled = true",
+            "[actuators.rotate]\nspeed = 1",
+            "[actuators.rotate]\nmin_apm = \"fast\"",
+            "[actuators.linear]\nmin_apm = 90",
+            "devices = 1",
+            "[devices.\"/(/\"]\nscale = 0.5",
+            "[devices.Toy]\nspeed = 1",
+            "[devices.Toy]\nactuators = [\"wiggle\"]",
+            "[devices.Toy]\nmax_level = 1.5",
+            "[devices.Toy]\nmin_level = 0.6\nmax_level = 0.4",
+            "[devices.Toy]\nsteps = 0",
+            "[devices.Toy]\nmin_command_interval = \"soon\"",
+        ] {
+            let mut file = tempfile::Builder::new().suffix(".toml").tempfile().unwrap();
+            file.write_all(config.as_bytes()).unwrap();
+            let cli_args: Vec<OsString> =
+                vec!["starplug".into(), "--config".into(), file.path().into()];
+            assert!(
+                parse_args_from(cli_args).is_err(),
+                "{config} should be rejected"
+            );
+        }
+    }
+}
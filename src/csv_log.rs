@@ -0,0 +1,84 @@
+//! Log APM history to a CSV file for looking at after a game.
+
+use crate::events::{Event, EventSink};
+use anyhow::{anyhow, Result};
+use chrono::{SecondsFormat, Utc};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use tokio::sync::mpsc;
+use tracing::warn;
+
+const CSV_HEADER: &str = "timestamp,apm,level,marker\n";
+
+/// Appends a row for every APM change, and a marker row when a game starts or stops.
+/// Rows are written straight to the file, so a crash loses at most the row being written.
+pub struct CsvSink {
+    file: File,
+    warned: bool,
+    /// Whether rows come from [`CsvSink::log_samples`] instead of APM events.
+    every_sample: bool,
+}
+
+impl CsvSink {
+    /// Open the file for appending, writing a header first if it's new.
+    pub fn new(path: &Path) -> Result<Self> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| {
+                anyhow!(e).context(format!(
+                    "Couldn't open CSV log {path}",
+                    path = path.display()
+                ))
+            })?;
+        if file.metadata()?.len() == 0 {
+            file.write_all(CSV_HEADER.as_bytes())?;
+        }
+        Ok(Self {
+            file,
+            warned: false,
+            every_sample: false,
+        })
+    }
+
+    /// Log every sample from the APM source, with no level, instead of APM changes.
+    /// Samples are written from their own task, through their own handle to the file.
+    pub fn log_samples(&mut self, mut samples: mpsc::Receiver<i32>) -> Result<()> {
+        let mut file = self.file.try_clone()?;
+        self.every_sample = true;
+        tokio::spawn(async move {
+            let mut warned = false;
+            while let Some(apm) = samples.recv().await {
+                let timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+                if let Err(e) = file.write_all(format!("{timestamp},{apm},,\n").as_bytes()) {
+                    if !warned {
+                        warn!("Couldn't write to CSV log: {e}. Further errors won't be logged.");
+                        warned = true;
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+impl EventSink for CsvSink {
+    fn send(&mut self, event: &Event) {
+        let timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+        let row = match event {
+            Event::Apm { .. } if self.every_sample => return,
+            Event::Apm { apm, level } => format!("{timestamp},{apm},{level},\n"),
+            Event::GameStarted => format!("{timestamp},,,game_started\n"),
+            Event::GameStopped => format!("{timestamp},,,game_stopped\n"),
+            _ => return,
+        };
+        if let Err(e) = self.file.write_all(row.as_bytes()) {
+            if !self.warned {
+                warn!("Couldn't write to CSV log: {e}. Further errors won't be logged.");
+                self.warned = true;
+            }
+        }
+    }
+}
@@ -0,0 +1,143 @@
+//! Remember each device's `--device-scale` between runs, so a device only needs setting up once.
+//!
+//! Settings are kept by device name in `devices.toml`, next to the config file.
+//! A scale given with `--device-scale` always wins over a remembered one, and then replaces it.
+//! `--forget-device-scale` forgets a device's remembered scale.
+
+use crate::mapper::DeviceScale;
+use crate::state_file::write_atomically;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// Where remembered device settings are kept.
+/// On macOS, this is `~/Library/Application Support/StarPlug/devices.toml`.
+pub fn default_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("StarPlug").join("devices.toml"))
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct DeviceMemory {
+    #[serde(default)]
+    devices: BTreeMap<String, RememberedDevice>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq)]
+struct RememberedDevice {
+    scale: f64,
+}
+
+impl DeviceMemory {
+    /// Read remembered settings. A missing file means nothing is remembered yet,
+    /// and a file we can't read is ignored, since StarPlug works fine without it.
+    pub fn load(path: &Path) -> Self {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Self::default(),
+            Err(e) => {
+                warn!(
+                    "Couldn't read remembered device settings from {path}: {e}",
+                    path = path.display()
+                );
+                return Self::default();
+            }
+        };
+        toml::from_str(&text).unwrap_or_else(|e| {
+            warn!(
+                "Ignoring remembered device settings in {path}: {e}",
+                path = path.display()
+            );
+            Self::default()
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        write_atomically(path, toml::to_string(self)?.as_bytes())
+    }
+
+    /// Remember the scales given this time, replacing any remembered for the same devices.
+    /// Returns whether anything changed.
+    pub fn remember(&mut self, device_scales: &[DeviceScale]) -> bool {
+        let mut changed = false;
+        for device_scale in device_scales {
+            let device = RememberedDevice {
+                scale: device_scale.scale,
+            };
+            if self.devices.get(&device_scale.name) != Some(&device) {
+                self.devices.insert(device_scale.name.clone(), device);
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Forget what's remembered about these devices. Returns whether anything changed.
+    pub fn forget(&mut self, names: &[String]) -> bool {
+        let mut changed = false;
+        for name in names {
+            changed |= self.devices.remove(name).is_some();
+        }
+        changed
+    }
+
+    /// Every remembered scale.
+    pub fn device_scales(&self) -> Vec<DeviceScale> {
+        self.devices
+            .iter()
+            .map(|(name, device)| DeviceScale {
+                name: name.clone(),
+                scale: device.scale,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scale(name: &str, scale: f64) -> DeviceScale {
+        DeviceScale {
+            name: name.to_owned(),
+            scale,
+        }
+    }
+
+    #[test]
+    fn remember_reports_only_changes() {
+        let mut memory = DeviceMemory::default();
+        assert!(!memory.remember(&[]));
+        assert!(memory.remember(&[scale("Lovense Hush", 0.6)]));
+        assert!(!memory.remember(&[scale("Lovense Hush", 0.6)]));
+        assert!(memory.remember(&[scale("Lovense Hush", 0.8)]));
+        assert_eq!(memory.device_scales(), vec![scale("Lovense Hush", 0.8)]);
+    }
+
+    #[test]
+    fn forget_removes_remembered_scales() {
+        let mut memory = DeviceMemory::default();
+        memory.remember(&[scale("Lovense Hush", 0.6), scale("Lovense Lush", 0.8)]);
+        assert!(memory.forget(&["Lovense Hush".to_owned()]));
+        assert!(!memory.forget(&["Lovense Hush".to_owned()]));
+        assert_eq!(memory.device_scales(), vec![scale("Lovense Lush", 0.8)]);
+    }
+
+    #[test]
+    fn memory_round_trips_through_toml() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("StarPlug").join("devices.toml");
+        assert!(DeviceMemory::load(&path).device_scales().is_empty());
+        let mut memory = DeviceMemory::default();
+        memory.remember(&[scale("Lovense Hush", 0.6)]);
+        memory.save(&path).unwrap();
+        assert_eq!(
+            DeviceMemory::load(&path).device_scales(),
+            vec![scale("Lovense Hush", 0.6)]
+        );
+    }
+}
@@ -0,0 +1,160 @@
+//! Show current APM in Discord Rich Presence.
+
+use crate::events::{Event, Events};
+use crate::DisplayUnits;
+use discord_rich_presence::activity::Activity;
+use discord_rich_presence::{DiscordIpc, DiscordIpcClient};
+use futures::{select, FutureExt};
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::oneshot;
+use tokio::task::spawn_blocking;
+use tokio::time::interval;
+use tracing::{info, warn};
+
+/// Discord only allows a few activity updates every 20 seconds, so don't update more often than this.
+const DISCORD_UPDATE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// What Discord is showing.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Presence {
+    game_running: bool,
+    apm: i32,
+}
+
+/// Handle to the task keeping Discord up to date.
+pub struct DiscordPresence {
+    shutdown_tx: oneshot::Sender<()>,
+    finished_rx: oneshot::Receiver<()>,
+}
+
+impl DiscordPresence {
+    /// Clear StarPlug's activity from Discord and disconnect.
+    pub async fn close(self) {
+        let _ = self.shutdown_tx.send(());
+        let _ = self.finished_rx.await;
+    }
+}
+
+/// Start updating Discord Rich Presence from events.
+/// Does nothing while Discord isn't running, and starts showing APM once it is.
+pub fn spawn_discord_presence(
+    events: &Events,
+    app_id: String,
+    display_units: DisplayUnits,
+) -> DiscordPresence {
+    let (shutdown_tx, shutdown_rx) = oneshot::channel();
+    let (finished_tx, finished_rx) = oneshot::channel();
+    let mut rx = events.subscribe();
+
+    tokio::spawn(async move {
+        let mut client: Option<DiscordIpcClient> = None;
+        let mut presence = Presence {
+            game_running: false,
+            apm: 0,
+        };
+        let mut shown: Option<Presence> = None;
+        let mut warned_not_running = false;
+        let mut tick = interval(DISCORD_UPDATE_INTERVAL);
+        let mut shutdown_rx = shutdown_rx.fuse();
+
+        loop {
+            select! {
+                event = rx.recv().fuse() => match event {
+                    Ok(Event::GameStarted) => presence.game_running = true,
+                    Ok(Event::GameStopped) => presence = Presence { game_running: false, apm: 0 },
+                    Ok(Event::Apm { apm, .. }) => presence.apm = apm,
+                    Ok(_) | Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => break,
+                },
+                _ = tick.tick().fuse() => {
+                    if shown == Some(presence) {
+                        continue;
+                    }
+                    let app_id = app_id.clone();
+                    let (details, state) = if presence.game_running {
+                        (
+                            format!(
+                                "Playing StarCraft — {rate}",
+                                rate = display_units.format(presence.apm as f64)
+                            ),
+                            "In game",
+                        )
+                    } else {
+                        ("Waiting for StarCraft".to_owned(), "Idle")
+                    };
+                    // Discord IPC is blocking.
+                    let result = spawn_blocking(move || {
+                        show(client, &app_id, &details, state)
+                    })
+                    .await;
+                    match result {
+                        Ok((new_client, Ok(()))) => {
+                            client = new_client;
+                            shown = Some(presence);
+                            if warned_not_running {
+                                info!("Connected to Discord.");
+                                warned_not_running = false;
+                            }
+                        }
+                        Ok((new_client, Err(e))) => {
+                            client = new_client;
+                            shown = None;
+                            if !warned_not_running {
+                                info!("Couldn't update Discord (is it running?): {e}. Will keep trying.");
+                                warned_not_running = true;
+                            }
+                        }
+                        Err(e) => {
+                            warn!("Discord update task failed: {e}");
+                            client = None;
+                            shown = None;
+                        }
+                    }
+                }
+                _ = shutdown_rx => break,
+            }
+        }
+
+        if let Some(mut client) = client {
+            let _ = spawn_blocking(move || {
+                let _ = client.clear_activity();
+                let _ = client.close();
+            })
+            .await;
+        }
+        let _ = finished_tx.send(());
+    });
+
+    DiscordPresence {
+        shutdown_tx,
+        finished_rx,
+    }
+}
+
+/// Connect to Discord if we aren't already, then show an activity.
+/// Returns the client if it's still connected.
+fn show(
+    client: Option<DiscordIpcClient>,
+    app_id: &str,
+    details: &str,
+    state: &str,
+) -> (
+    Option<DiscordIpcClient>,
+    Result<(), discord_rich_presence::error::Error>,
+) {
+    let mut client = match client {
+        Some(client) => client,
+        None => {
+            let mut client = DiscordIpcClient::new(app_id);
+            if let Err(e) = client.connect() {
+                return (None, Err(e));
+            }
+            client
+        }
+    };
+    match client.set_activity(Activity::new().details(details).state(state)) {
+        Ok(()) => (Some(client), Ok(())),
+        Err(e) => (None, Err(e)),
+    }
+}
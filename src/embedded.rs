@@ -0,0 +1,28 @@
+//! Run a Buttplug server inside StarPlug with `--embedded`, so Intiface Central isn't needed.
+//!
+//! Only Bluetooth LE devices are supported, and only on macOS.
+
+use anyhow::Result;
+use buttplug::core::connector::ButtplugInProcessClientConnector;
+
+#[cfg(target_os = "macos")]
+pub fn embedded_connector() -> Result<ButtplugInProcessClientConnector> {
+    use anyhow::anyhow;
+    use buttplug::core::connector::ButtplugInProcessClientConnectorBuilder;
+    use buttplug::server::device::hardware::communication::btleplug::BtlePlugCommunicationManagerBuilder;
+    use buttplug::server::ButtplugServerBuilder;
+
+    let server = ButtplugServerBuilder::default()
+        .name("StarPlug")
+        .comm_manager(BtlePlugCommunicationManagerBuilder::default())
+        .finish()
+        .map_err(|e| anyhow!(e).context("Couldn't start the embedded Buttplug server."))?;
+    Ok(ButtplugInProcessClientConnectorBuilder::default()
+        .server(server)
+        .finish())
+}
+
+#[cfg(not(target_os = "macos"))]
+pub fn embedded_connector() -> Result<ButtplugInProcessClientConnector> {
+    anyhow::bail!("The embedded Buttplug server is only supported on macOS.")
+}
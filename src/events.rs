@@ -0,0 +1,301 @@
+//! Events describing what StarPlug is doing, for other programs to consume.
+
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Utc};
+use nix::errno::Errno;
+use nix::fcntl::OFlag;
+use nix::sys::stat::Mode;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{info, warn};
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    IntifaceConnected {
+        server: String,
+    },
+    IntifaceDisconnected {
+        server: String,
+    },
+    DeviceAdded {
+        server: String,
+        name: String,
+        /// StarPlug will drive it: it's allowed, and has an actuator from `--actuators`.
+        driven: bool,
+    },
+    DeviceRemoved {
+        server: String,
+        name: String,
+    },
+    GameStarted,
+    GameStopped,
+    Apm {
+        apm: i32,
+        level: f64,
+    },
+    /// Battery level from 0 to 1.
+    Battery {
+        name: String,
+        level: f64,
+    },
+}
+
+/// An event with the time it happened, as written by sinks that serialize events.
+#[derive(Serialize)]
+pub struct TimestampedEvent<'a> {
+    pub timestamp: DateTime<Utc>,
+    #[serde(flatten)]
+    pub event: &'a Event,
+}
+
+impl<'a> TimestampedEvent<'a> {
+    pub fn now(event: &'a Event) -> Self {
+        Self {
+            timestamp: Utc::now(),
+            event,
+        }
+    }
+}
+
+/// StarPlug's current state, built up from events.
+#[derive(Serialize, Default)]
+pub struct State {
+    pub apm: i32,
+    pub level: f64,
+    /// Connected to at least one Intiface server.
+    pub connected: bool,
+    /// Intiface servers we're connected to.
+    pub servers: BTreeSet<String>,
+    pub devices: BTreeSet<Device>,
+    /// Latest battery level of each device that reports one.
+    pub batteries: BTreeMap<String, f64>,
+    pub game_running: bool,
+    /// Filled in by whoever publishes the state, rather than by events.
+    pub uptime_secs: f64,
+    pub updated: Option<DateTime<Utc>>,
+}
+
+/// A device connected to one of the Intiface servers.
+#[derive(Serialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Device {
+    pub server: String,
+    pub name: String,
+    pub driven: bool,
+}
+
+impl State {
+    /// Update the state to reflect an event.
+    pub fn apply(&mut self, event: &Event) {
+        match event {
+            Event::IntifaceConnected { server } => {
+                self.servers.insert(server.clone());
+                self.connected = true;
+            }
+            Event::IntifaceDisconnected { server } => {
+                self.servers.remove(server);
+                self.connected = !self.servers.is_empty();
+                self.remove_devices(|device| device.server == *server);
+            }
+            Event::DeviceAdded {
+                server,
+                name,
+                driven,
+            } => {
+                self.devices.insert(Device {
+                    server: server.clone(),
+                    name: name.clone(),
+                    driven: *driven,
+                });
+            }
+            Event::DeviceRemoved { server, name } => {
+                self.remove_devices(|device| device.server == *server && device.name == *name);
+            }
+            Event::GameStarted => self.game_running = true,
+            Event::GameStopped => {
+                self.game_running = false;
+                self.level = 0.0;
+            }
+            Event::Apm { apm, level } => {
+                self.apm = *apm;
+                self.level = *level;
+            }
+            Event::Battery { name, level } => {
+                self.batteries.insert(name.clone(), *level);
+            }
+        }
+    }
+
+    /// Forget some devices, and the battery levels of any names no longer connected anywhere.
+    fn remove_devices(&mut self, remove: impl Fn(&Device) -> bool) {
+        self.devices.retain(|device| !remove(device));
+        let devices = &self.devices;
+        self.batteries
+            .retain(|name, _| devices.iter().any(|device| device.name == *name));
+    }
+
+    /// Devices StarPlug is driving.
+    pub fn driven_devices(&self) -> impl Iterator<Item = &Device> {
+        self.devices.iter().filter(|device| device.driven)
+    }
+}
+
+/// Something that consumes StarPlug events.
+/// Sinks run on their own task, but should still never block for long.
+pub trait EventSink: Send + 'static {
+    fn send(&mut self, event: &Event);
+}
+
+/// Events can be sent to any number of sinks.
+/// Sending never blocks, and sinks that fall behind miss events rather than slowing StarPlug down.
+#[derive(Clone)]
+pub struct Events {
+    tx: broadcast::Sender<Event>,
+}
+
+/// Number of events a slow sink can fall behind by before it starts missing them.
+const EVENT_BUFFER: usize = 64;
+
+impl Events {
+    pub fn new() -> Self {
+        let (tx, _) = broadcast::channel(EVENT_BUFFER);
+        Self { tx }
+    }
+
+    /// Send an event to every sink.
+    pub fn emit(&self, event: Event) {
+        // Fails only if there are no sinks, which is fine.
+        let _ = self.tx.send(event);
+    }
+
+    /// Receive events directly, for consumers that need more than an `EventSink`.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.tx.subscribe()
+    }
+
+    /// Spawn a task that feeds events to a sink.
+    pub fn add_sink(&self, mut sink: impl EventSink) {
+        let mut rx = self.tx.subscribe();
+        tokio::spawn(async move {
+            loop {
+                match rx.recv().await {
+                    Ok(event) => sink.send(&event),
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+}
+
+/// Writes JSON event lines to stdout, for piping into another program.
+#[derive(Default)]
+pub struct StdoutSink {
+    /// Set once stdout is closed, so we stop trying.
+    closed: bool,
+}
+
+impl EventSink for StdoutSink {
+    fn send(&mut self, event: &Event) {
+        if self.closed {
+            return;
+        }
+        let line = match serde_json::to_string(&TimestampedEvent::now(event)) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("Couldn't serialize event: {e}");
+                return;
+            }
+        };
+        let mut stdout = std::io::stdout().lock();
+        if let Err(e) = writeln!(stdout, "{line}").and_then(|_| stdout.flush()) {
+            warn!("Couldn't write event to stdout, so no more will be written: {e}");
+            self.closed = true;
+        }
+    }
+}
+
+/// Writes JSON event lines to a named pipe.
+/// Events are dropped while nobody is reading the pipe or when the pipe is full.
+pub struct FifoSink {
+    path: PathBuf,
+    fifo: Option<File>,
+}
+
+impl FifoSink {
+    /// Create the FIFO if it doesn't exist yet.
+    pub fn new(path: &Path) -> Result<Self> {
+        match path.metadata() {
+            Ok(metadata) => {
+                if !metadata.file_type().is_fifo() {
+                    bail!("{path} exists but isn't a FIFO!", path = path.display());
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                nix::unistd::mkfifo(path, Mode::S_IRUSR | Mode::S_IWUSR).map_err(|e| {
+                    anyhow!(e).context(format!(
+                        "Couldn't create event FIFO at {path}",
+                        path = path.display()
+                    ))
+                })?;
+                info!("Created event FIFO at {path}", path = path.display());
+            }
+            Err(e) => return Err(anyhow!(e)),
+        }
+        Ok(Self {
+            path: path.to_owned(),
+            fifo: None,
+        })
+    }
+
+    /// Open the write end of the FIFO without blocking.
+    /// Fails with `ENXIO` if there's no reader yet.
+    fn open(&self) -> std::io::Result<File> {
+        OpenOptions::new()
+            .write(true)
+            .custom_flags(OFlag::O_NONBLOCK.bits())
+            .open(&self.path)
+    }
+}
+
+impl EventSink for FifoSink {
+    fn send(&mut self, event: &Event) {
+        if self.fifo.is_none() {
+            match self.open() {
+                Ok(fifo) => self.fifo = Some(fifo),
+                Err(e) if e.raw_os_error() == Some(Errno::ENXIO as i32) => return,
+                Err(e) => {
+                    warn!("Couldn't open event FIFO: {e}");
+                    return;
+                }
+            }
+        }
+        let Some(fifo) = self.fifo.as_mut() else {
+            return;
+        };
+
+        let mut line = match serde_json::to_vec(&TimestampedEvent::now(event)) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("Couldn't serialize event: {e}");
+                return;
+            }
+        };
+        line.push(b'\n');
+
+        // Lines are shorter than `PIPE_BUF`, so the write is atomic: all or nothing.
+        match fifo.write(&line) {
+            Ok(_) => {}
+            // Pipe is full because the reader is slow. Drop this event.
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+            // Reader went away. Reopen when the next event arrives.
+            Err(_) => self.fifo = None,
+        }
+    }
+}
@@ -0,0 +1,57 @@
+//! Check whether StarCraft is the frontmost app.
+
+use anyhow::{anyhow, bail, Result};
+use std::time::Duration;
+use tokio::process::Command;
+use tokio::sync::watch;
+use tokio::time::interval;
+use tracing::warn;
+
+/// How often to check which app is frontmost.
+const FOCUS_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Get the name of the frontmost app using macOS's `lsappinfo`.
+async fn frontmost_app_name() -> Result<String> {
+    let front = Command::new("lsappinfo").arg("front").output().await?;
+    if !front.status.success() {
+        bail!("`lsappinfo front` failed with status {}", front.status);
+    }
+    let asn = String::from_utf8_lossy(&front.stdout).trim().to_owned();
+
+    let info = Command::new("lsappinfo")
+        .args(["info", "-only", "name"])
+        .arg(&asn)
+        .output()
+        .await?;
+    // Output looks like `"LSDisplayName"="StarCraft"`.
+    String::from_utf8_lossy(&info.stdout)
+        .trim()
+        .rsplit_once('=')
+        .map(|(_, name)| name.trim_matches('"').to_owned())
+        .ok_or(anyhow!("Couldn't parse `lsappinfo info` output"))
+}
+
+/// Watch whether the app with the given name is frontmost.
+/// If we can't tell which app is frontmost, assume it always is.
+pub fn watch_focus(app_name: &'static str) -> watch::Receiver<bool> {
+    let (focused_tx, focused_rx) = watch::channel(true);
+    tokio::spawn(async move {
+        let mut poll = interval(FOCUS_POLL_INTERVAL);
+        loop {
+            poll.tick().await;
+            match frontmost_app_name().await {
+                Ok(name) => {
+                    if focused_tx.send(name == app_name).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    warn!("Couldn't tell which app is frontmost, so vibration won't pause when {app_name} loses focus: {e}");
+                    let _ = focused_tx.send(true);
+                    break;
+                }
+            }
+        }
+    });
+    focused_rx
+}
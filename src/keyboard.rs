@@ -0,0 +1,91 @@
+//! Estimate APM from global keyboard and mouse activity, for when we can't read it from StarCraft.
+//!
+//! Only the times of key presses and mouse clicks are used: which keys were pressed and where the
+//! mouse was are never looked at or stored.
+
+use crate::apm_stream::Status;
+use anyhow::Result;
+use tokio::sync::watch;
+
+/// Actions are counted over this window and scaled up to a per-minute rate.
+#[cfg(target_os = "macos")]
+const KEYBOARD_APM_WINDOW: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Recalculate APM this often.
+#[cfg(target_os = "macos")]
+const KEYBOARD_APM_TICK: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Start counting key presses and mouse clicks.
+/// The returned channel closes if input monitoring stops, usually because it wasn't permitted.
+#[cfg(target_os = "macos")]
+pub fn watch_keyboard_apm(smoothing_alpha: f64) -> Result<watch::Receiver<Status>> {
+    use crate::mapper::Ewma;
+    use futures::{select, FutureExt};
+    use std::collections::VecDeque;
+    use std::time::Instant;
+    use tokio::sync::mpsc;
+    use tracing::error;
+
+    let (action_tx, mut action_rx) = mpsc::unbounded_channel::<Instant>();
+
+    // `rdev::listen` blocks forever, so it needs its own thread.
+    std::thread::spawn(move || {
+        let result = rdev::listen(move |event| {
+            if matches!(
+                event.event_type,
+                rdev::EventType::KeyPress(_) | rdev::EventType::ButtonPress(_)
+            ) {
+                let _ = action_tx.send(Instant::now());
+            }
+        });
+        if let Err(e) = result {
+            error!(
+                "Couldn't monitor keyboard and mouse: {e:?}. \
+                Make sure your terminal app is allowed under \
+                System Settings → Privacy & Security → Input Monitoring."
+            );
+        }
+    });
+
+    let (apm_tx, apm_rx) = watch::channel(Status::Apm(0));
+
+    tokio::spawn(async move {
+        let mut actions = VecDeque::<Instant>::new();
+        let mut tick = tokio::time::interval(KEYBOARD_APM_TICK);
+        let mut prev_apm = 0i32;
+        let mut ewma = Ewma::new(smoothing_alpha);
+        loop {
+            select! {
+                action = action_rx.recv().fuse() => match action {
+                    Some(action) => actions.push_back(action),
+                    None => break,
+                },
+                _ = tick.tick().fuse() => {
+                    while let Some(action) = actions.front() {
+                        if action.elapsed() <= KEYBOARD_APM_WINDOW {
+                            break;
+                        }
+                        actions.pop_front();
+                    }
+                    let apm = (actions.len() as f64 * 60.0 / KEYBOARD_APM_WINDOW.as_secs_f64())
+                        as i32;
+                    let apm = ewma.update(apm);
+                    if apm == prev_apm {
+                        continue;
+                    }
+                    prev_apm = apm;
+                    if apm_tx.send(Status::Apm(apm)).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+
+    Ok(apm_rx)
+}
+
+#[cfg(not(target_os = "macos"))]
+pub fn watch_keyboard_apm(_smoothing_alpha: f64) -> Result<watch::Receiver<Status>> {
+    anyhow::bail!("Keyboard APM is only supported on macOS.")
+}
@@ -0,0 +1,87 @@
+//! Start StarPlug automatically at login on macOS.
+
+use anyhow::{anyhow, Result};
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::process::Stdio;
+use tracing::{info, warn};
+
+const LABEL: &str = "com.github.VyrCossont.StarPlug";
+
+/// Template for the launch agent. `{label}`, `{program_arguments}`, and `{log_path}` are filled in.
+const LAUNCHAGENT_PLIST: &str = include_str!("launchagent.plist");
+
+fn home_dir() -> Result<PathBuf> {
+    std::env::var_os("HOME").map(PathBuf::from).ok_or(anyhow!(
+        "Couldn't find your home directory: HOME isn't set."
+    ))
+}
+
+fn plist_path() -> Result<PathBuf> {
+    Ok(home_dir()?
+        .join("Library/LaunchAgents")
+        .join(format!("{LABEL}.plist")))
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Write a launch agent that runs this StarPlug executable with the given arguments.
+pub fn install(args: &[OsString]) -> Result<()> {
+    let exe = std::env::current_exe()?;
+    let mut program_arguments = String::new();
+    for arg in std::iter::once(exe.as_os_str()).chain(args.iter().map(|arg| arg.as_os_str())) {
+        let arg = arg.to_str().ok_or(anyhow!(
+            "Can't put non-UTF-8 argument {arg:?} in a launch agent."
+        ))?;
+        program_arguments.push_str(&format!("        <string>{}</string>\n", xml_escape(arg)));
+    }
+    let log_path = home_dir()?.join("Library/Logs/StarPlug.log");
+    let plist = LAUNCHAGENT_PLIST
+        .replace("{label}", LABEL)
+        .replace("{log_path}", &xml_escape(&log_path.to_string_lossy()))
+        .replace("{program_arguments}", &program_arguments);
+
+    let path = plist_path()?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(&path, plist)?;
+
+    info!("Wrote launch agent to {path}", path = path.display());
+    info!(
+        "StarPlug will start when you next log in, or you can start it now with `launchctl load {path}`.",
+        path = path.display()
+    );
+    info!(
+        "Its output will go to {log_path}",
+        log_path = log_path.display()
+    );
+    warn!(
+        "StarPlug started by launchd can't show the prompt asking permission to debug StarCraft. \
+        If it can't attach, enable developer mode by running `sudo DevToolsSecurity -enable`."
+    );
+    Ok(())
+}
+
+/// Stop and remove the launch agent, if there is one.
+pub fn uninstall() -> Result<()> {
+    let path = plist_path()?;
+    if !path.exists() {
+        info!("No launch agent to remove at {path}", path = path.display());
+        return Ok(());
+    }
+    // Fails if the agent isn't loaded, which is fine.
+    let _ = std::process::Command::new("launchctl")
+        .arg("unload")
+        .arg(&path)
+        .stderr(Stdio::null())
+        .status();
+    std::fs::remove_file(&path)?;
+    info!("Removed launch agent from {path}", path = path.display());
+    Ok(())
+}
@@ -1,25 +1,64 @@
-use anyhow::{anyhow, bail, Result};
-use buttplug::client::{ButtplugClient, ButtplugClientDevice, ButtplugClientEvent, VibrateCommand};
+mod apm_stream;
+mod calibrate;
+mod config;
+mod csv_log;
+mod device_memory;
+mod discord;
+mod embedded;
+mod events;
+mod focus;
+mod keyboard;
+mod launchagent;
+mod mapper;
+mod metrics;
+mod osc;
+mod pattern;
+mod replay;
+mod sc2;
+mod state_file;
+mod tui;
+
+use crate::apm_stream::{DriveMetric, Status, StatusParser};
+use crate::config::DeviceSettings;
+use crate::events::{Event, Events, FifoSink, StdoutSink};
+use crate::mapper::{
+    ActuatorMapping, ActuatorThresholds, ApmMapper, Curve, CurvePoints, Deadzone, DeviceBand,
+    DeviceScale,
+};
+use crate::pattern::Pattern;
+use anyhow::{anyhow, bail, Context, Result};
+use buttplug::client::{
+    ButtplugClient, ButtplugClientDevice, ButtplugClientError, ButtplugClientEvent, LinearCommand,
+    RotateCommand, ScalarCommand,
+};
 use buttplug::core::connector::{ButtplugRemoteClientConnector, ButtplugWebsocketClientTransport};
 use buttplug::core::message::serializer::ButtplugClientJSONSerializer;
 use buttplug::core::message::ActuatorType;
-use clap::Parser;
-use futures::{select, FutureExt, StreamExt};
-use nix;
-use std::ffi::OsString;
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use futures::future::{join_all, pending};
+use futures::{select, FutureExt, Stream, StreamExt};
+use regex::Regex;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::ffi::{OsStr, OsString};
 use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::process::Stdio;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
-use sysinfo::{Pid, ProcessExt, ProcessRefreshKind, RefreshKind, System, SystemExt};
-use tempfile;
+use std::time::{Duration, Instant, SystemTime};
+use sysinfo::{Pid, PidExt, ProcessExt, ProcessRefreshKind, RefreshKind, System, SystemExt};
 use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::process::Command;
+use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::oneshot::error::TryRecvError;
-use tokio::sync::{oneshot, watch, Mutex};
-use tokio::time::{sleep, timeout};
+use tokio::sync::{mpsc, oneshot, watch, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, sleep_until, timeout};
 use tokio::{signal, spawn};
-use tracing::{error, info, warn};
+use tracing::{debug, error, info, warn};
+use tracing_subscriber::filter::{EnvFilter, LevelFilter};
+use tracing_subscriber::prelude::*;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about)]
@@ -29,9 +68,99 @@ use tracing::{error, info, warn};
 ///
 /// StarPlug on macOS requires `lldb`; you can install it with the Xcode command-line tools by running `xcode-select --install`.
 struct Args {
+    /// Read settings from this TOML file. Arguments given on the command line override it.
+    /// Defaults to `config.toml` in StarPlug's folder in your config directory
+    /// (`~/Library/Application Support/StarPlug` on macOS), if it exists.
+    #[arg(long)]
+    config: Option<PathBuf>,
+
     /// Intiface websocket URL to connect to.
+    /// Can be given more than once to drive devices on several Intiface servers at once.
     #[arg(long, default_value = "ws://localhost:12345")]
-    server: String,
+    server: Vec<String>,
+
+    /// Wait this long between attempts to connect to Intiface, like `5s` or `500ms`.
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "5s")]
+    reconnect_interval: Duration,
+
+    /// Accept self-signed or otherwise invalid TLS certificates from a `wss://` server.
+    #[arg(long, default_value_t = false)]
+    allow_invalid_certs: bool,
+
+    /// Run a Buttplug server inside StarPlug instead of connecting to Intiface Central.
+    /// Finds Bluetooth LE devices itself. macOS only.
+    #[arg(long, default_value_t = false)]
+    embedded: bool,
+
+    /// Stop scanning for new devices this many seconds after connecting to Intiface.
+    /// 0 scans for as long as StarPlug runs.
+    #[arg(long, default_value_t = 0)]
+    scan_seconds: u64,
+
+    /// Warn when a device's battery drops below this level, from 0 to 1.
+    #[arg(long)]
+    battery_warn: Option<f64>,
+
+    /// Ignore APM and play a test pattern until Ctrl-C, for finding a comfortable range:
+    /// `sweep` ramps from 0 to the max intensity and back, and `pulse:<N>hz` pulses on and off.
+    /// StarCraft isn't needed.
+    #[arg(long, conflicts_with = "replay")]
+    pattern: Option<Pattern>,
+
+    /// When a device disconnects, scan for it again for a while, even after `--scan-seconds` is up,
+    /// and bring it back to the current level once it's found.
+    #[arg(long, default_value_t = false)]
+    reconnect_devices: bool,
+
+    /// Record APM during play for `--calibrate-duration`, then suggest `--min-apm` and `--max-apm`.
+    /// Doesn't connect to Intiface.
+    #[arg(long, default_value_t = false, conflicts_with = "pattern")]
+    calibrate: bool,
+
+    /// How much play `--calibrate` records, like `2m` or `90s`.
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "2m")]
+    calibrate_duration: Duration,
+
+    /// Quit after StarCraft goes away once, instead of waiting for it to be relaunched.
+    #[arg(long, default_value_t = false)]
+    once: bool,
+
+    /// Don't connect to Intiface. Just log APM and the vibration levels it maps to.
+    #[arg(long, default_value_t = false)]
+    dry_run: bool,
+
+    /// Show a dashboard with APM, vibration level, and connections instead of a scrolling log.
+    #[arg(long, default_value_t = false)]
+    tui: bool,
+
+    /// After connecting to Intiface, run each actuator on each device at a low level for a second,
+    /// to check that they respond.
+    #[arg(long, default_value_t = false)]
+    self_test: bool,
+
+    /// Executable names to look for StarCraft under, separated by commas.
+    /// A trailing `.exe` is ignored, so `StarCraft` also finds `StarCraft.exe`.
+    #[arg(long, value_delimiter = ',', default_value = "StarCraft")]
+    process_name: Vec<String>,
+
+    /// Have lldb run this Python script instead of StarPlug's own, for working out where a new version of
+    /// StarCraft keeps its APM. It's read again every time lldb starts, so changes don't need a restart.
+    /// See `src/starplug.py` for what it needs to print.
+    #[arg(long)]
+    script: Option<PathBuf>,
+
+    /// Look for StarCraft by a substring of its command line instead of by executable name,
+    /// for when the executable name isn't distinctive.
+    #[arg(long)]
+    match_cmdline: Option<String>,
+
+    /// Attach to the StarCraft process with this PID instead of looking for one.
+    #[arg(long)]
+    pid: Option<u32>,
+
+    /// Executable name of Intiface Central, used to check whether it's running when we can't connect.
+    #[arg(long, default_value = "intiface_central")]
+    intiface_process_name: String,
 
     /// Don't vibrate below this APM.
     #[arg(long, default_value_t = 60)]
@@ -41,372 +170,3988 @@ struct Args {
     #[arg(long, default_value_t = 120)]
     max_apm: i32,
 
-    /// Show lldb errors (only useful for debugging, most aren't signficant).
+    /// Once APM drops below the min APM, don't start vibrating again until it's this much above it,
+    /// so APM hovering around the min doesn't turn vibration on and off.
+    #[arg(long, default_value_t = 0)]
+    deadzone: i32,
+
+    /// Smooth APM before mapping it to vibration, from 0 (no smoothing) to 1.
+    /// Lower values smooth more, at the cost of reacting more slowly.
+    #[arg(long, default_value_t = 0.0)]
+    smoothing_alpha: f64,
+
+    /// Stop vibrating if your average APM over the last minute stays below this,
+    /// even if StarCraft is still reporting APM changes (for example, if you're AFK in a game).
+    #[arg(long)]
+    min_session_apm: Option<i32>,
+
+    /// Relaxation mode: vibrate hardest at or below the min APM, and stop at or above the max APM.
     #[arg(long, default_value_t = false)]
-    show_lldb_errors: bool,
-}
+    invert: bool,
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
+    /// While a game is running, never vibrate below this level (from 0 to 1), even below the min APM.
+    /// Vibration still stops completely when the game does.
+    #[arg(long, default_value_t = 0.0)]
+    floor: f64,
 
-    let args = Args::parse();
-    if args.max_apm <= args.min_apm {
-        bail!("Max APM must be strictly greater than min APM!");
-    }
-    if args.min_apm < 0 {
-        bail!("APM values cannot be negative!");
-    }
+    /// Instead of vibrating at full strength for any APM above the max, start easing off at this
+    /// vibration level (from 0 to 1) so that vibration keeps getting stronger as APM rises,
+    /// approaching full strength without reaching it.
+    #[arg(long)]
+    compress_knee: Option<f64>,
 
-    check_prereqs().await?;
+    /// Shape of the ramp from min APM to max APM: `linear`, `quadratic` (gentle, then steep near the top),
+    /// `sqrt` (steep, then gentle near the top), or `exp` (like quadratic but adjustable, for example `exp:2.5`).
+    #[arg(long, default_value = "linear")]
+    curve: Curve,
 
-    info!("Type Ctrl-C to quit StarPlug.");
+    /// Map APM to vibration with your own table instead, like `40:0.0,80:0.4,120:0.9,160:1.0`,
+    /// going in straight lines between the points. APM must increase from point to point.
+    /// Overrides `--min-apm`, `--max-apm`, `--compress-knee`, and `--curve`, since the table already says
+    /// where vibration starts and peaks. `--deadzone` still uses `--min-apm`.
+    #[arg(long)]
+    curve_points: Option<CurvePoints>,
 
-    info!("Connecting to Intiface…");
-    let client = Arc::new(Mutex::new(ButtplugClient::new("StarPlug")));
-    let server = args.server.clone();
-    connect_to_buttplug(server.clone(), client.clone()).await?;
-    spawn(stay_connected_to_buttplug(server.clone(), client.clone()));
-    info!("Connected to Intiface.");
+    /// Briefly pulse your vibrator when your APM reaches a new multiple of this in a game.
+    #[arg(long)]
+    milestone_step: Option<i32>,
 
-    let running_lldb: Arc<Mutex<Option<ChildShutdown>>> = Arc::new(Mutex::new(None));
+    /// Vibration level for milestone pulses, from 0 to 1.
+    #[arg(long, default_value_t = 1.0)]
+    milestone_level: f64,
 
-    loop {
-        select! {
-            signal_result = signal::ctrl_c().fuse() => {
-                if signal_result.is_err() {
-                    // This probably won't happen unless we can't install a Ctrl-C handler.
-                    return signal_result.map_err(|e| anyhow!(e));
-                }
-                if let Some(lldb) = running_lldb.lock().await.take() {
-                    info!("Waiting for lldb to terminate…");
-                    lldb.terminate().await?;
-                    info!("lldb terminated.");
-                }
-                return Ok(());
-            }
-            sync_result = sync_apm_to_vibrators(&args, client.clone(), running_lldb.clone()).fuse() => {
-                if sync_result.is_err() {
-                    return sync_result;
-                }
-                info!("Lost connection to StarCraft.");
-                info!("Waiting for StarCraft to be relaunched…");
-            }
-        }
-    }
-}
+    /// Length of milestone pulses in milliseconds.
+    #[arg(long, default_value_t = 300)]
+    milestone_ms: u64,
 
-async fn check_prereqs() -> Result<()> {
-    let exit_status = Command::new("lldb")
-        .arg("--version")
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .spawn()
-        .map_err(|e| anyhow!(e).context("Couldn't run `lldb --version`. Make sure it's installed by running `xcode-select --install`."))?
-        .wait()
-        .await?;
-    if !exit_status.success() {
-        bail!("`lldb --version` failed with status {exit_status}. Make sure it's installed by running `xcode-select --install`.");
-    }
-    Ok(())
+    /// Kinds of actuators to drive, separated by commas, for example `vibrate,rotate`.
+    #[arg(long, value_enum, value_delimiter = ',', default_value = "vibrate")]
+    actuators: Vec<Actuator>,
+
+    /// Position that strokers move back to, from 0 to 1.
+    #[arg(long, default_value_t = 0.0)]
+    stroke_min_pos: f64,
+
+    /// Position that strokers move out to, from 0 to 1.
+    #[arg(long, default_value_t = 1.0)]
+    stroke_max_pos: f64,
+
+    /// Squeeze that constricting actuators start at once APM is above the minimum, from 0 to 1.
+    #[arg(long, default_value_t = 0.0)]
+    constrict_min: f64,
+
+    /// Squeeze that constricting actuators reach at the maximum APM, from 0 to 1.
+    #[arg(long, default_value_t = 1.0)]
+    constrict_max: f64,
+
+    /// Scale the level sent to the device with this name, like `--device-scale "Lovense Hush=0.6"`.
+    /// Can be given more than once. Devices not named here aren't scaled.
+    #[arg(long)]
+    device_scale: Vec<DeviceScale>,
+
+    /// Don't remember `--device-scale` settings for next time, or use the ones remembered from last time.
+    /// Otherwise, a device keeps the scale it was last given until it's given a new one.
+    /// `--dry-run`, `--calibrate`, and `--pattern` runs use remembered scales but don't change them.
+    #[arg(long, default_value_t = false)]
+    no_device_memory: bool,
+
+    /// Forget the scale remembered for the device with this name, so it's not scaled anymore.
+    /// Can be given more than once.
+    #[arg(long, conflicts_with = "no_device_memory")]
+    forget_device_scale: Vec<String>,
+
+    /// Scales remembered from earlier runs, used for devices that `--device-scale` doesn't name.
+    #[arg(skip)]
+    remembered_device_scale: Vec<DeviceScale>,
+
+    /// Have devices follow their own APM ranges, like `--device-band "Toy A=40-90,Toy B=80-160"`.
+    /// A banded device goes from 0 at its min to full at its max, and is off outside its range.
+    /// Banded devices follow APM directly, without ramping, fading out, or soft starting,
+    /// but still get milestone pulses.
+    #[arg(long, value_delimiter = ',')]
+    device_band: Vec<DeviceBand>,
+
+    /// Thresholds and curves for single actuator types, from `[actuators.<type>]` tables in the config file.
+    #[arg(skip)]
+    actuator_thresholds: Vec<(Actuator, ActuatorThresholds)>,
+
+    /// Settings for single devices, from the `[devices]` table in the config file.
+    #[arg(skip)]
+    device_settings: Vec<DeviceSettings>,
+
+    /// Drive the vibration motors of multi-motor devices differently, like `0=follow,1=fixed:0.3`
+    /// to have motor 0 follow APM while motor 1 holds at 0.3.
+    /// Motors are numbered from 0 in the order Intiface lists a device's actuators.
+    /// Motors not listed follow APM.
+    #[arg(long, value_delimiter = ',')]
+    actuator_map: Vec<ActuatorMapping>,
+
+    /// Only vibrate devices whose names match this regular expression.
+    /// There's no separate filter for exact names: anchor the regex, like `^Lovense Hush$`, to pick out one device.
+    #[arg(long)]
+    device_name_regex: Option<Regex>,
+
+    /// Don't vibrate devices whose names match this regular expression.
+    /// Applied after `--device-name-regex`.
+    #[arg(long)]
+    device_name_regex_deny: Option<Regex>,
+
+    /// Never send a level above this to any device, from 0 to 1, whatever the APM and other settings.
+    #[arg(long, default_value_t = 1.0)]
+    max_intensity: f64,
+
+    /// Limit the sum of vibration levels across all vibrators to this.
+    /// For example, with a budget of 1 and two vibrators, each vibrates at no more than 0.5.
+    #[arg(long)]
+    total_intensity_budget: Option<f64>,
+
+    /// When APM drops, let vibration fade down over up to this many milliseconds
+    /// (the time to fall from full vibration to none) instead of dropping immediately.
+    #[arg(long)]
+    engagement_decay_ms: Option<u64>,
+
+    /// Limit how fast the vibration level can change, in levels per second (full vibration is 1),
+    /// so vibration ramps up and down smoothly instead of jumping. Stopping is always immediate.
+    #[arg(long)]
+    ramp_rate: Option<f64>,
+
+    /// Send each device a new level at most this often, like `50ms`, for Bluetooth devices that can't keep up.
+    /// Levels in between are skipped, but the latest one is always sent.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    min_command_interval: Option<Duration>,
+
+    /// When a game ends, fade vibration out over this many seconds instead of stopping at once.
+    /// Ctrl-C still stops immediately.
+    #[arg(long)]
+    fade_out_seconds: Option<f64>,
+
+    /// When a game starts, ease vibration in from nothing over this many seconds
+    /// instead of jumping straight to the level for the current APM.
+    #[arg(long)]
+    soft_start_seconds: Option<f64>,
+
+    /// After StarCraft connects, wait this many seconds before sending any vibration commands.
+    /// APM is still tracked and logged while waiting.
+    #[arg(long, default_value_t = 0)]
+    arm_delay: u64,
+
+    /// Pause vibration while StarCraft isn't the frontmost app.
+    #[arg(long, default_value_t = false)]
+    freeze_on_focus_loss: bool,
+
+    /// Ignore this many APM values after each time we connect to StarCraft.
+    /// The first value is often left over in memory from an earlier game, rather than from the current one.
+    #[arg(long, default_value_t = 1)]
+    skip_first_samples: u32,
+
+    /// If APM drops to exactly zero mid-game, keep the previous vibration level for this many
+    /// milliseconds before acting on it, in case it's a momentary glitch.
+    #[arg(long, default_value_t = 0)]
+    zero_hold_ms: u64,
+
+    /// Quit after StarPlug has been running this long, like `90m` or `1h 30m`,
+    /// stopping devices the same way Ctrl-C does.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    max_session: Option<Duration>,
+
+    /// Stop vibrating when APM hasn't changed for this long, like `3s` or `500ms`,
+    /// since the game has probably finished or been paused.
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "3s")]
+    game_timeout: Duration,
+
+    /// Stop vibrating if the level stays barely above zero for this many seconds,
+    /// until APM picks back up.
+    #[arg(long)]
+    idle_timeout: Option<u64>,
+
+    /// Print JSON event lines to stdout, each with a `type` and a `timestamp`.
+    /// Logs go to stderr instead.
+    #[arg(long, default_value_t = false, conflicts_with = "tui")]
+    json: bool,
+
+    /// Write JSON event lines to this named pipe, creating it if necessary.
+    /// Events are dropped while nothing is reading from the pipe.
+    #[arg(long)]
+    event_fifo: Option<PathBuf>,
+
+    /// Where to get APM from: `lldb` to read it from StarCraft's in-game APM display,
+    /// `keyboard` to estimate it from how often you press keys and click the mouse in any app
+    /// (needs Input Monitoring permission on macOS, and never looks at which keys you pressed),
+    /// `pipe:<path>` to read `APM: N` lines from a named pipe that your own debugger script writes to,
+    /// or `tcp:<host>:<port>` to listen for those lines on a TCP socket.
+    #[arg(long, default_value = "lldb")]
+    apm_source: ApmSource,
+
+    /// Which StarCraft you're playing. StarCraft II doesn't tell anyone its APM,
+    /// so with `sc2`, the `lldb` APM source estimates it from the keyboard and mouse instead,
+    /// and StarCraft II's client API is used to only vibrate during games.
+    #[arg(long, value_enum, default_value_t = Game::Sc1)]
+    game: Game,
+
+    /// Average APM over this many seconds, like in-game APM meters do, before smoothing it.
+    /// Raw APM is used until the first full window.
+    #[arg(long)]
+    apm_window_seconds: Option<u64>,
+
+    /// Which value from the APM source's status lines drives vibration: `apm`, `supply`, `minerals`,
+    /// or `clock` (game time in seconds). `--min-apm` and `--max-apm` are in that value's units.
+    /// Only the `lldb`, `pipe`, and `tcp` APM sources send anything other than APM.
+    #[arg(long, value_enum, default_value_t = DriveMetric::Apm)]
+    drive_metric: DriveMetric,
+
+    /// Replay APM from a CSV file written by `--log-csv` instead of getting it from `--apm-source`.
+    #[arg(long)]
+    replay: Option<PathBuf>,
+
+    /// Replay this many times faster than the APM was recorded. Less than 1 slows it down.
+    /// 0 replays as fast as possible without driving any devices, for checking mappings.
+    /// Otherwise, it must be at least 0.001.
+    /// Faster than 1 sends each device a new level at most every 100 ms, unless `--min-command-interval` says otherwise.
+    #[arg(long, default_value_t = 1.0)]
+    replay_speed: f64,
+
+    /// Send APM and vibration levels as OSC messages to this `host:port`,
+    /// on `/starplug/apm` and `/starplug/level`. `host:port/path` sends on `<path>/apm` and `<path>/level` instead.
+    /// Can be given more than once, to send to several destinations.
+    #[arg(long, visible_alias = "osc-addr", value_name = "HOST:PORT[/PATH]")]
+    osc_out: Vec<String>,
+
+    /// Append a row with the time, APM, and vibration level to this CSV file every time APM changes,
+    /// plus a row whenever a game starts or stops.
+    #[arg(long)]
+    log_csv: Option<PathBuf>,
+
+    /// With `--log-csv`, log every sample from the APM source instead of only the changes that drive vibration.
+    /// Samples are logged before `--apm-window-seconds` and `--smoothing-alpha`, and without a level.
+    /// Vibration only follows the latest APM, so if the log falls behind, it drops samples instead of holding vibration up.
+    /// Only works with the `lldb`, `pipe`, and `tcp` APM sources.
+    #[arg(long, requires = "log_csv", default_value_t = false)]
+    log_csv_every_sample: bool,
+
+    /// Serve Prometheus metrics at `http://<HOST:PORT>/metrics`.
+    #[arg(long, value_name = "HOST:PORT")]
+    metrics_addr: Option<String>,
+
+    /// Keep this file up to date with a JSON snapshot of StarPlug's state.
+    #[arg(long)]
+    state_file: Option<PathBuf>,
+
+    /// Rewrite the state file this often in milliseconds, instead of after every change.
+    #[arg(long)]
+    state_file_interval_ms: Option<u64>,
+
+    /// Reconnect to Intiface and StarCraft if nothing at all happens for a long time,
+    /// in case something is stuck.
+    #[arg(long, default_value_t = false)]
+    watchdog: bool,
+
+    /// How long the watchdog waits before reconnecting, in minutes.
+    #[arg(long, default_value_t = 30)]
+    watchdog_minutes: u64,
+
+    /// Units for APM in log messages.
+    /// Only affects what's shown: `--min-apm` and other options are always in actions per minute.
+    #[arg(long, value_enum, default_value_t = DisplayUnits::Apm)]
+    display_units: DisplayUnits,
+
+    /// Least important messages to log. `RUST_LOG` overrides this when it's set.
+    #[arg(long, value_enum, default_value_t = LogLevel::Info)]
+    log_level: LogLevel,
+
+    /// Also write logs to this file, starting a new one each day with the date added to the name.
+    #[arg(long)]
+    log_file: Option<PathBuf>,
+
+    /// Only log warnings and errors. Short for `--log-level warn`.
+    #[arg(long, default_value_t = false, conflicts_with = "log_level")]
+    quiet: bool,
+
+    /// Show your APM in Discord Rich Presence, using the Discord application with this ID.
+    /// Create an application at https://discord.com/developers/applications to get one;
+    /// its name is what Discord will show you as playing.
+    #[arg(long, value_name = "APPLICATION_ID")]
+    discord_rpc: Option<String>,
+
+    /// Show lldb errors (only useful for debugging, most aren't signficant).
+    /// Errors that stop lldb from attaching to StarCraft are always reported.
+    #[arg(long, default_value_t = false)]
+    show_lldb_errors: bool,
+
+    #[command(subcommand)]
+    action: Option<Action>,
 }
 
-/// Wait this long between attempts to connect to Intiface.
-const BUTTPLUG_WAIT: Duration = Duration::from_secs(5);
+#[derive(Subcommand, Debug)]
+enum Action {
+    /// Start StarPlug automatically when you log in, using the other arguments given with this one.
+    InstallLaunchagent,
+    /// Stop starting StarPlug automatically when you log in.
+    UninstallLaunchagent,
+}
 
-/// Connect to an Intiface server.
-async fn connect_to_buttplug(server: String, client: Arc<Mutex<ButtplugClient>>) -> Result<()> {
-    while let Err(e) = client
-        .lock()
-        .await
-        .connect(ButtplugRemoteClientConnector::<
-            ButtplugWebsocketClientTransport,
-            ButtplugClientJSONSerializer,
-        >::new(
-            ButtplugWebsocketClientTransport::new_insecure_connector(&server),
-        ))
-        .await
-    {
-        warn!("Couldn't connect to Intiface: {e}");
-        info!("Please make sure the Intiface server is running and listening at {server}. Waiting {wait:?} and trying again…", wait = BUTTPLUG_WAIT);
-        sleep(BUTTPLUG_WAIT).await;
-    }
-    info!("Connected to Intiface.");
-    client
-        .lock()
-        .await
-        .start_scanning()
-        .await
-        .map_err(|e| anyhow!(e).context("Couldn't start scanning for vibrators."))
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum ApmSource {
+    /// Read APM from StarCraft's in-game APM display using lldb.
+    Lldb,
+    /// Estimate APM from how often you press keys and click the mouse, in any app.
+    Keyboard,
+    /// Read `APM: N` lines from a named pipe.
+    Pipe(PathBuf),
+    /// Listen for `APM: N` lines on a TCP socket at this address.
+    Tcp(String),
 }
 
-async fn stay_connected_to_buttplug(server: String, client: Arc<Mutex<ButtplugClient>>) {
-    let mut client_events = client.lock().await.event_stream();
-    while let Some(event) = client_events.next().await {
-        match event {
-            ButtplugClientEvent::ServerDisconnect => {
-                warn!("Disconnected from Intiface. Vibration disabled. Attempting to reconnect…");
-                if let Err(e) = connect_to_buttplug(server.clone(), client.clone()).await {
-                    error!("Error while reconnecting to Intiface: {e}");
-                }
-                info!("Reconnected to Intiface. Vibration enabled.");
-            }
-            ButtplugClientEvent::Error(e) => {
-                error!("Intiface client error: {e}");
-            }
-            ButtplugClientEvent::PingTimeout => {
-                error!("Intiface client ping timeout!");
-            }
-            _ => {}
+impl FromStr for ApmSource {
+    type Err = String;
+
+    /// Parse `lldb`, `keyboard`, `pipe:<path>`, or `tcp:<host>:<port>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "lldb" => Ok(ApmSource::Lldb),
+            None if s == "keyboard" => Ok(ApmSource::Keyboard),
+            Some(("pipe", path)) if !path.is_empty() => Ok(ApmSource::Pipe(path.into())),
+            Some(("tcp", addr)) if !addr.is_empty() => Ok(ApmSource::Tcp(addr.to_owned())),
+            _ => Err(format!(
+                "Unknown APM source {s:?}: expected lldb, keyboard, pipe:<path>, or tcp:<host>:<port>"
+            )),
         }
     }
 }
 
-trait ButtplugClientDeviceExt {
-    fn is_vibrator(&self) -> bool;
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Game {
+    /// StarCraft and Brood War, including Remastered.
+    Sc1,
+    /// StarCraft II.
+    Sc2,
 }
 
-impl ButtplugClientDeviceExt for ButtplugClientDevice {
-    fn is_vibrator(&self) -> bool {
-        if let Some(scalar_cmds) = self.message_attributes().scalar_cmd() {
-            return scalar_cmds
-                .iter()
-                .find(|scalar_cmd| *scalar_cmd.actuator_type() == ActuatorType::Vibrate)
-                .is_some();
+impl Game {
+    /// Name of the game's app, as macOS shows it.
+    fn app_name(self) -> &'static str {
+        match self {
+            Game::Sc1 => "StarCraft",
+            Game::Sc2 => "StarCraft II",
         }
-        false
     }
 }
 
-/// When it's been this long since the last APM change, stop all vibrators.
-const GAME_RUNNING_WAIT: Duration = Duration::from_secs(3);
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Actuator {
+    /// Vibration motors.
+    Vibrate,
+    /// Rotation motors, like the Lovense Nora's. Speed follows APM the same way vibration does.
+    Rotate,
+    /// Oscillating motors, found in some newer toys instead of or alongside vibration motors.
+    /// Speed follows APM the same way vibration does.
+    Oscillate,
+    /// Constricting or inflating actuators. Squeeze follows APM between `--constrict-min`
+    /// and `--constrict-max`.
+    Constrict,
+    /// Strokers, which move back and forth between `--stroke-min-pos` and `--stroke-max-pos`,
+    /// faster at higher APM.
+    Linear,
+}
 
-/// Monitor StarCraft.
-/// Send vibration commands when APM changes.
-/// Stop all vibrators if we don't get an APM change for a while.
-async fn sync_apm_to_vibrators(
-    args: &Args,
-    client: Arc<Mutex<ButtplugClient>>,
-    running_lldb: Arc<Mutex<Option<ChildShutdown>>>,
-) -> Result<()> {
-    info!("Starting lldb…");
-    let mut apm_rx = connect_to_starcraft(args.show_lldb_errors, running_lldb).await?;
-    info!("lldb started.");
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum DisplayUnits {
+    /// Actions per minute.
+    Apm,
+    /// Actions per second.
+    Aps,
+}
 
-    let mut game_running = false;
-    loop {
-        match timeout(GAME_RUNNING_WAIT, apm_rx.changed()).await {
-            Ok(Ok(())) => {
-                if !game_running {
-                    info!("Connected to StarCraft: received first APM change.");
-                    game_running = true;
-                }
-                let apm = *apm_rx.borrow_and_update();
-                apm_changed(args, apm, client.clone()).await;
-            }
-            Ok(Err(e)) => {
-                error!("APM channel closed: {e}");
-                stop_all_vibrators(client.clone()).await;
-                return Ok(());
-            }
-            Err(_) => {
-                if game_running {
-                    info!(
-                        "APM hasn't changed in a while. \
-                        The current game may have finished or StarCraft may be paused."
-                    );
-                    game_running = false;
-                    stop_all_vibrators(client.clone()).await;
-                }
-            }
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => LevelFilter::ERROR,
+            LogLevel::Warn => LevelFilter::WARN,
+            LogLevel::Info => LevelFilter::INFO,
+            LogLevel::Debug => LevelFilter::DEBUG,
+            LogLevel::Trace => LevelFilter::TRACE,
         }
     }
 }
 
-/// Python script that we ask `lldb` to run.
-/// Writes status lines like `APM: 69`.
-const STARPLUG_PY: &[u8] = include_bytes!("starplug.py");
+impl DisplayUnits {
+    /// Format an APM value for people to read, with its units.
+    fn format(self, apm: f64) -> String {
+        match self {
+            DisplayUnits::Apm => format!("{apm:.0} APM"),
+            DisplayUnits::Aps => format!("{aps:.1} APS", aps = apm / 60.0),
+        }
+    }
+}
 
-/// Launch `lldb` with our instrumentation script and start tracking APM.
-/// May need to wait for StarCraft to be started.
-async fn connect_to_starcraft(
-    show_lldb_errors: bool,
-    running_lldb: Arc<Mutex<Option<ChildShutdown>>>,
-) -> Result<watch::Receiver<i32>> {
-    // Write our internal copy of the lldb script to a temp file.
-    let mut starplug_py = tempfile::Builder::new()
-        .prefix("starplug_")
-        .suffix(".py")
-        .tempfile()?;
-    starplug_py.write_all(STARPLUG_PY)?;
-    let starplug_py_path = starplug_py.path();
+impl Args {
+    /// Are we estimating APM from the keyboard and mouse?
+    fn apm_from_keyboard(&self) -> bool {
+        self.apm_source == ApmSource::Keyboard
+            || (self.apm_source == ApmSource::Lldb && self.game == Game::Sc2)
+    }
 
-    // Build an lldb command to run the script.
-    let mut script_arg = OsString::new();
-    script_arg.push("command script import '");
-    script_arg.push(starplug_py_path.as_os_str());
-    script_arg.push("'");
+    /// Multiplier for levels sent to a device with this name.
+    /// `--device-scale` wins over the device's `[devices]` entry, which wins over a remembered scale.
+    fn device_scale(&self, name: &str) -> f64 {
+        let given = self
+            .device_scale
+            .iter()
+            .find(|device_scale| device_scale.name == name)
+            .map(|device_scale| device_scale.scale);
+        let remembered = || {
+            self.remembered_device_scale
+                .iter()
+                .find(|device_scale| device_scale.name == name)
+                .map(|device_scale| device_scale.scale)
+        };
+        given
+            .or_else(|| self.device_entry(name).and_then(|entry| entry.scale))
+            .or_else(remembered)
+            .unwrap_or(1.0)
+    }
 
-    // Start lldb with that command.
-    // lldb dumps a lot of symbol-related errors when loading the StarCraft binary,
-    // but none of them matter.
-    let mut lldb_cmd = Command::new("lldb");
+    /// The `[devices]` entry for a device with this name, if it has one.
+    fn device_entry(&self, name: &str) -> Option<&DeviceSettings> {
+        DeviceSettings::find(&self.device_settings, name)
+    }
 
-    lldb_cmd
-        .args(["--batch", "--source-quietly", "--one-line"])
-        .arg(script_arg)
-        .stdout(Stdio::piped())
-        .stderr(if show_lldb_errors {
-            Stdio::inherit()
-        } else {
-            Stdio::null()
-        });
+    /// Actuator types to drive on a device with this name: from its `[devices]` entry, or else `--actuators`.
+    fn device_actuators(&self, name: &str) -> &[Actuator] {
+        self.device_entry(name)
+            .and_then(|entry| entry.actuators.as_deref())
+            .unwrap_or(&self.actuators)
+    }
 
-    if let Some(pid) = find_starcraft_pid() {
-        info!("StarCraft is already running: PID {pid}");
-        lldb_cmd.env("STARCRAFT_PID", pid.to_string());
-    } else {
-        info!("StarCraft is not running yet.");
+    /// Motor roles for a device with this name: from its `[devices]` entry, or else `--actuator-map`.
+    fn device_actuator_map(&self, name: &str) -> &[ActuatorMapping] {
+        self.device_entry(name)
+            .and_then(|entry| entry.actuator_map.as_deref())
+            .unwrap_or(&self.actuator_map)
     }
 
-    let mut lldb = lldb_cmd.spawn()?;
+    /// The `--device-band` for a device with this name, if it has one.
+    fn device_band(&self, name: &str) -> Option<&DeviceBand> {
+        self.device_band.iter().find(|band| band.name == name)
+    }
 
-    let lldb_stdout = lldb
-        .stdout
-        .take()
-        .ok_or(anyhow!("Couldn't get lldb's stdout!"))?;
-    let mut lldb_reader = BufReader::new(lldb_stdout).lines();
+    /// Level for the device with this name, one of `devices`, while the shared level is `level`.
+    /// Banded devices use the latest APM instead, once there is one.
+    fn device_level(
+        &self,
+        mapper: &ApmMapper,
+        name: &str,
+        devices: usize,
+        level: f64,
+        apm: Option<i32>,
+    ) -> f64 {
+        let level = match (self.device_band(name), apm) {
+            (Some(band), Some(apm)) => mapper.band_level(band, apm),
+            _ => level,
+        };
+        let level = mapper.device_level(level, devices, self.device_scale(name));
+        match self.device_entry(name) {
+            Some(entry) => mapper.cap(entry.level(level)),
+            None => level,
+        }
+    }
 
-    let (apm_tx, apm_rx) = watch::channel(0i32);
+    /// Like [`Args::device_level`], for one of the device's actuator types.
+    /// A type with its own thresholds maps the latest APM itself, unless the device has a band.
+    fn actuator_level(
+        &self,
+        mapper: &ApmMapper,
+        actuator: Actuator,
+        name: &str,
+        devices: usize,
+        level: f64,
+        apm: Option<i32>,
+    ) -> f64 {
+        let thresholds = self
+            .actuator_thresholds
+            .iter()
+            .find(|(a, _)| *a == actuator)
+            .map(|(_, thresholds)| thresholds);
+        let level = match (thresholds, apm) {
+            (Some(thresholds), Some(apm)) if self.device_band(name).is_none() => {
+                mapper.with_thresholds(thresholds).level(apm)
+            }
+            _ => level,
+        };
+        self.device_level(mapper, name, devices, level, apm)
+    }
 
-    // Spawn a task to watch for APM info from lldb.
-    let _ = tokio::spawn(async move {
-        let mut prev_apm = 0i32;
-        while let Ok(Some(line)) = lldb_reader.next_line().await {
-            if let Some(apm_str_ws) = line.strip_prefix("APM:") {
-                if let Ok(apm) = apm_str_ws.trim().parse::<i32>() {
-                    if apm == prev_apm {
-                        continue;
-                    }
-                    prev_apm = apm;
-                    if let Err(e) = apm_tx.send(apm) {
-                        error!("Couldn't send APM through watch channel: {e:?}");
-                        break;
-                    }
-                }
+    /// Will we drive this device: is it allowed, and does it have an actuator from `--actuators`?
+    fn drives(&self, device: &ButtplugClientDevice) -> bool {
+        self.allows_device(device.name())
+            && self
+                .device_actuators(device.name())
+                .iter()
+                .any(|actuator| device.has_actuator(*actuator))
+    }
+
+    /// Should we send commands to a device with this name?
+    fn allows_device(&self, name: &str) -> bool {
+        if let Some(allow) = &self.device_name_regex {
+            if !allow.is_match(name) {
+                return false;
             }
         }
-        info!("lldb reader task finished.");
-    });
-
-    let (finished_tx, finished_rx) = oneshot::channel::<()>();
-    let pid = lldb.id().ok_or(anyhow!("Couldn't get lldb PID!"))? as i32;
-    *running_lldb.lock().await = Some(ChildShutdown { pid, finished_rx });
+        if let Some(deny) = &self.device_name_regex_deny {
+            if deny.is_match(name) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let started = Instant::now();
+    let (mut args, sources) = config::parse_args()?;
+    let log_level = if args.quiet {
+        LogLevel::Warn
+    } else {
+        args.log_level
+    };
+    let log_filter = EnvFilter::builder()
+        .with_default_directive(LevelFilter::from(log_level).into())
+        .from_env_lossy();
+    let console_layer = if args.tui {
+        tracing_subscriber::fmt::layer()
+            .with_ansi(false)
+            .with_writer(tui::LogWriter::default)
+            .boxed()
+    } else if args.json {
+        // Keep stdout for events.
+        tracing_subscriber::fmt::layer()
+            .with_writer(std::io::stderr)
+            .boxed()
+    } else {
+        tracing_subscriber::fmt::layer().boxed()
+    };
+    // Held until we exit so buffered lines get written.
+    let mut _log_file_guard = None;
+    let file_layer = match &args.log_file {
+        Some(path) => {
+            let file_name = path.file_name().ok_or(anyhow!(
+                "Log file {path} needs a file name",
+                path = path.display()
+            ))?;
+            let dir = path.parent().unwrap_or(Path::new(""));
+            let appender = tracing_appender::rolling::daily(dir, file_name);
+            let (writer, guard) = tracing_appender::non_blocking(appender);
+            _log_file_guard = Some(guard);
+            Some(
+                tracing_subscriber::fmt::layer()
+                    .with_ansi(false)
+                    .with_writer(writer),
+            )
+        }
+        None => None,
+    };
+    tracing_subscriber::registry()
+        .with(console_layer)
+        .with(file_layer)
+        .with(log_filter)
+        .init();
+    if args.replay.is_some() && args.replay_speed == 0.0 && !args.dry_run {
+        info!("Replaying as fast as possible, so not driving any devices.");
+        args.dry_run = true;
+    }
+    // Installing or uninstalling the launch agent doesn't drive anything, so there's nothing to remember.
+    if !args.no_device_memory && args.action.is_none() {
+        if let Some(path) = device_memory::default_path() {
+            let mut memory = device_memory::DeviceMemory::load(&path);
+            // Dry runs, calibration, and patterns use remembered scales, but aren't real sessions to remember.
+            let trial_run = args.dry_run || args.calibrate || args.pattern.is_some();
+            let forgot = memory.forget(&args.forget_device_scale);
+            let remembered = memory.remember(&args.device_scale);
+            if (forgot || remembered) && !trial_run {
+                if let Err(e) = memory.save(&path) {
+                    warn!(
+                        "Couldn't save device settings to {path}: {e}",
+                        path = path.display()
+                    );
+                }
+            }
+            args.remembered_device_scale = memory.device_scales();
+        }
+    }
+    for server in &mut args.server {
+        let normalized = normalize_server(server)
+            .with_context(|| format!("Bad Intiface server (set {})", sources.of("server")))?;
+        if normalized != *server {
+            info!("Using Intiface server {normalized} for {server}.");
+            *server = normalized;
+        }
+    }
+    if args.replay.is_some() && args.replay_speed > 1.0 && args.min_command_interval.is_none() {
+        args.min_command_interval = Some(FAST_REPLAY_COMMAND_INTERVAL);
+    }
+    let args = Arc::new(args);
+    if args.embedded {
+        if args.server.len() > 1 {
+            bail!(
+                "--embedded runs a single server of its own, so it can't be used with more than one --server! (set {source})",
+                source = sources.of("server"),
+            );
+        }
+    } else {
+        let mut any_tls = false;
+        for server in &args.server {
+            any_tls |= uses_tls(server)?;
+        }
+        if !any_tls && args.allow_invalid_certs {
+            warn!("--allow-invalid-certs has no effect with a ws:// server.");
+        }
+    }
+    if args.max_apm <= args.min_apm {
+        bail!(
+            "Max APM ({max_apm}, set {max_source}) must be strictly greater than min APM ({min_apm}, set {min_source})!",
+            max_apm = args.max_apm,
+            max_source = sources.of("max_apm"),
+            min_apm = args.min_apm,
+            min_source = sources.of("min_apm"),
+        );
+    }
+    for (actuator, thresholds) in &args.actuator_thresholds {
+        let min_apm = thresholds.min_apm.unwrap_or(args.min_apm);
+        let max_apm = thresholds.max_apm.unwrap_or(args.max_apm);
+        if max_apm <= min_apm || min_apm < 0 {
+            bail!(
+                "[actuators.{name}] in the config file needs a max APM ({max_apm}) strictly greater than its min APM ({min_apm}), which can't be negative!",
+                name = actuator.to_possible_value().map_or_else(String::new, |v| v.get_name().to_owned()),
+            );
+        }
+    }
+    if args.min_apm < 0 {
+        bail!(
+            "APM values cannot be negative! (min APM was set {source})",
+            source = sources.of("min_apm")
+        );
+    }
+    if args.min_session_apm.unwrap_or(0) < 0 {
+        bail!(
+            "APM values cannot be negative! (min session APM was set {source})",
+            source = sources.of("min_session_apm")
+        );
+    }
+    if args.milestone_step.is_some_and(|step| step <= 0) {
+        bail!(
+            "Milestone step must be positive! (set {source})",
+            source = sources.of("milestone_step")
+        );
+    }
+    if args
+        .total_intensity_budget
+        .is_some_and(|budget| budget <= 0.0)
+    {
+        bail!(
+            "Total intensity budget must be positive! (set {source})",
+            source = sources.of("total_intensity_budget")
+        );
+    }
+    if args
+        .compress_knee
+        .is_some_and(|knee| !(0.0..1.0).contains(&knee))
+    {
+        bail!(
+            "Compressor knee must be at least 0 and less than 1! (set {source})",
+            source = sources.of("compress_knee")
+        );
+    }
+    if args.watchdog && args.watchdog_minutes == 0 {
+        bail!(
+            "Watchdog window must be positive! (set {source})",
+            source = sources.of("watchdog_minutes")
+        );
+    }
+    if args.state_file_interval_ms == Some(0) {
+        bail!(
+            "State file interval must be positive! (set {source})",
+            source = sources.of("state_file_interval_ms")
+        );
+    }
+    if args.ramp_rate.is_some_and(|rate| rate <= 0.0) {
+        bail!(
+            "Ramp rate must be positive! (set {source})",
+            source = sources.of("ramp_rate")
+        );
+    }
+    if args
+        .soft_start_seconds
+        .is_some_and(|seconds| seconds <= 0.0)
+    {
+        bail!(
+            "Soft start time must be positive! (set {source})",
+            source = sources.of("soft_start_seconds")
+        );
+    }
+    if args.fade_out_seconds.is_some_and(|seconds| seconds <= 0.0) {
+        bail!(
+            "Fade out time must be positive! (set {source})",
+            source = sources.of("fade_out_seconds")
+        );
+    }
+    if args.engagement_decay_ms == Some(0) {
+        bail!(
+            "Engagement decay must be positive! (set {source})",
+            source = sources.of("engagement_decay_ms")
+        );
+    }
+    for (id, pos) in [
+        ("stroke_min_pos", args.stroke_min_pos),
+        ("stroke_max_pos", args.stroke_max_pos),
+    ] {
+        if !(0.0..=1.0).contains(&pos) {
+            bail!(
+                "Stroke positions must be between 0 and 1! (set {source})",
+                source = sources.of(id)
+            );
+        }
+    }
+    if args.stroke_max_pos <= args.stroke_min_pos {
+        bail!(
+            "Stroke max position ({max_source}) must be greater than stroke min position ({min_source})!",
+            max_source = sources.of("stroke_max_pos"),
+            min_source = sources.of("stroke_min_pos"),
+        );
+    }
+    for (id, squeeze) in [
+        ("constrict_min", args.constrict_min),
+        ("constrict_max", args.constrict_max),
+    ] {
+        if !(0.0..=1.0).contains(&squeeze) {
+            bail!(
+                "Constriction bounds must be between 0 and 1! (set {source})",
+                source = sources.of(id)
+            );
+        }
+    }
+    if args.constrict_max < args.constrict_min {
+        bail!(
+            "Constrict max ({max_source}) must be at least constrict min ({min_source})!",
+            max_source = sources.of("constrict_max"),
+            min_source = sources.of("constrict_min"),
+        );
+    }
+    if !(0.0..=1.0).contains(&args.max_intensity) {
+        bail!(
+            "Max intensity must be between 0 and 1! (set {source})",
+            source = sources.of("max_intensity")
+        );
+    }
+    if args.max_intensity < 1.0 {
+        info!(
+            "Intensity is capped at {max_intensity}.",
+            max_intensity = args.max_intensity
+        );
+    }
+    if args.deadzone < 0 || args.min_apm + args.deadzone >= args.max_apm {
+        bail!(
+            "Deadzone must be at least 0 and end below max APM! (set {source})",
+            source = sources.of("deadzone")
+        );
+    }
+    if !(0.0..=1.0).contains(&args.floor) {
+        bail!(
+            "Floor must be between 0 and 1! (set {source})",
+            source = sources.of("floor")
+        );
+    }
+    if !(0.0..=1.0).contains(&args.smoothing_alpha) {
+        bail!(
+            "Smoothing alpha must be between 0 and 1! (set {source})",
+            source = sources.of("smoothing_alpha")
+        );
+    }
+    if !(0.0..=1.0).contains(&args.milestone_level) {
+        bail!(
+            "Milestone level must be between 0 and 1! (set {source})",
+            source = sources.of("milestone_level")
+        );
+    }
+
+    match args.action {
+        Some(Action::InstallLaunchagent) => {
+            let mut launchagent_args: Vec<OsString> = std::env::args_os().skip(1).collect();
+            // Take out only the subcommand, not an option value that happens to match it.
+            if let Some(index) = subcommand_index(&Args::command(), &launchagent_args) {
+                launchagent_args.remove(index);
+            }
+            return launchagent::install(&launchagent_args);
+        }
+        Some(Action::UninstallLaunchagent) => return launchagent::uninstall(),
+        None => {}
+    }
+
+    for (name, id, duration) in [
+        ("Game timeout", "game_timeout", args.game_timeout),
+        (
+            "Reconnect interval",
+            "reconnect_interval",
+            args.reconnect_interval,
+        ),
+        (
+            "Calibration duration",
+            "calibrate_duration",
+            args.calibrate_duration,
+        ),
+    ] {
+        if duration.is_zero() {
+            bail!(
+                "{name} must be positive! (set {source})",
+                source = sources.of(id)
+            );
+        }
+    }
+    if args
+        .battery_warn
+        .is_some_and(|level| !(0.0..=1.0).contains(&level))
+    {
+        bail!(
+            "Battery warning level must be between 0 and 1! (set {source})",
+            source = sources.of("battery_warn")
+        );
+    }
+    if !args.replay_speed.is_finite()
+        || (args.replay_speed != 0.0 && args.replay_speed < replay::MIN_REPLAY_SPEED)
+    {
+        bail!(
+            "Replay speed must be 0 or at least {min}! (set {source})",
+            min = replay::MIN_REPLAY_SPEED,
+            source = sources.of("replay_speed")
+        );
+    }
+    if let Some(path) = &args.script {
+        std::fs::File::open(path).with_context(|| {
+            format!(
+                "Couldn't read lldb script {path} (set {source})",
+                path = path.display(),
+                source = sources.of("script")
+            )
+        })?;
+    }
+    if args.apm_window_seconds == Some(0) {
+        bail!(
+            "APM window must be at least 1 second! (set {source})",
+            source = sources.of("apm_window_seconds")
+        );
+    }
+    let streams_status_lines = !args.apm_from_keyboard()
+        && matches!(
+            args.apm_source,
+            ApmSource::Lldb | ApmSource::Pipe(_) | ApmSource::Tcp(_)
+        );
+    if args.drive_metric != DriveMetric::Apm && (args.replay.is_some() || !streams_status_lines) {
+        bail!(
+            "Only APM can drive vibration with this APM source! (--drive-metric set {source})",
+            source = sources.of("drive_metric")
+        );
+    }
+    if args.log_csv_every_sample && (args.replay.is_some() || !streams_status_lines) {
+        bail!(
+            "Only the lldb, pipe, and tcp APM sources can log every sample! (--log-csv-every-sample set {source})",
+            source = sources.of("log_csv_every_sample")
+        );
+    }
+    // Every raw sample, for `--log-csv-every-sample`.
+    // Vibration sticks to watch channels, which only keep the latest value, so it never falls behind.
+    let (sample_tx, mut sample_rx) = if args.log_csv_every_sample {
+        let (sample_tx, sample_rx) = mpsc::channel(SAMPLE_BUFFER);
+        (Some(sample_tx), Some(sample_rx))
+    } else {
+        (None, None)
+    };
+    // APM from somewhere other than lldb.
+    let external_apm = match (&args.replay, &args.apm_source) {
+        // Test patterns don't need APM.
+        _ if args.pattern.is_some() => None,
+        (Some(path), _) => Some(replay::spawn_replay(path, args.replay_speed)?),
+        (None, ApmSource::Lldb) if args.game == Game::Sc2 => {
+            info!("Estimating StarCraft II APM from keyboard and mouse activity.");
+            Some(keyboard::watch_keyboard_apm(args.smoothing_alpha)?)
+        }
+        (None, ApmSource::Lldb) => {
+            check_prereqs().await?;
+            None
+        }
+        (None, ApmSource::Keyboard) => {
+            info!("Estimating APM from keyboard and mouse activity.");
+            Some(keyboard::watch_keyboard_apm(args.smoothing_alpha)?)
+        }
+        (None, ApmSource::Pipe(path)) => Some(apm_stream::watch_pipe(
+            path.clone(),
+            StatusParser::new(&args, sample_tx.clone()),
+        )),
+        (None, ApmSource::Tcp(addr)) => Some(
+            apm_stream::watch_tcp(addr.clone(), StatusParser::new(&args, sample_tx.clone()))
+                .await?,
+        ),
+    };
+
+    let external_apm = match external_apm {
+        Some(apm_rx) if args.game == Game::Sc2 && args.replay.is_none() => {
+            Some(sc2::gate_on_sc2_games(apm_rx))
+        }
+        external_apm => external_apm,
+    };
+
+    if args.calibrate {
+        let running_lldb: Arc<Mutex<Option<ChildShutdown>>> = Arc::new(Mutex::new(None));
+        let result = select! {
+            result = calibrate::calibrate(&args, external_apm, running_lldb.clone()).fuse() => result,
+            signal_result = signal::ctrl_c().fuse() => {
+                info!("Calibration cancelled.");
+                signal_result.map_err(|e| anyhow!(e))
+            }
+            signal_result = wait_for_sigterm().fuse() => {
+                info!("Got SIGTERM. Calibration cancelled.");
+                signal_result.map_err(|e| anyhow!(e))
+            }
+        };
+        if let Some(lldb) = running_lldb.lock().await.take() {
+            lldb.terminate().await?;
+        }
+        return result;
+    }
+
+    let events = Events::new();
+    if args.json {
+        events.add_sink(StdoutSink::default());
+    }
+    if let Some(path) = &args.event_fifo {
+        events.add_sink(FifoSink::new(path)?);
+    }
+    if let Some(path) = &args.log_csv {
+        let mut sink = csv_log::CsvSink::new(path)?;
+        if let Some(sample_rx) = sample_rx.take() {
+            sink.log_samples(sample_rx)?;
+        }
+        events.add_sink(sink);
+    }
+    if !args.osc_out.is_empty() {
+        events.add_sink(osc::OscSink::new(&args.osc_out)?);
+    }
+    if let Some(path) = &args.state_file {
+        state_file::spawn_state_file(
+            &events,
+            path.clone(),
+            args.state_file_interval_ms.map(Duration::from_millis),
+        );
+    }
+    let mut metrics = match &args.metrics_addr {
+        Some(addr) => Some(metrics::spawn_metrics_server(&events, addr).await?),
+        None => None,
+    };
+    let mut discord = args
+        .discord_rpc
+        .clone()
+        .map(|app_id| discord::spawn_discord_presence(&events, app_id, args.display_units));
+
+    info!("Type Ctrl-C to quit StarPlug.");
+
+    let clients: Clients = args
+        .server
+        .iter()
+        .map(|_| Arc::new(Mutex::new(ButtplugClient::new("StarPlug"))))
+        .collect();
+    let output = Output::default();
+    if args.dry_run {
+        info!("Dry run: not connecting to Intiface.");
+    } else {
+        info!("Connecting to Intiface…");
+        // Subscribe before connecting so we don't miss devices found during the first scan.
+        let mut client_events = vec![];
+        for client in clients.iter() {
+            client_events.push(client.lock().await.event_stream());
+        }
+        let connections = args
+            .server
+            .iter()
+            .zip(clients.iter())
+            .map(|(server, client)| connect_to_buttplug(&args, server, client, events.clone()));
+        let mut errors = vec![];
+        for (server, result) in args.server.iter().zip(join_all(connections).await) {
+            if let Err(e) = result {
+                error!("Error connecting to Intiface at {server}: {e}");
+                errors.push(e);
+            }
+        }
+        // One server failing shouldn't keep us from driving the devices on the others.
+        if errors.len() == clients.len() {
+            return Err(errors.remove(0));
+        }
+        if args.self_test {
+            self_test(&args, &clients, &output).await;
+        }
+        let strokes = args.actuators.contains(&Actuator::Linear)
+            || args.device_settings.iter().any(|entry| {
+                entry
+                    .actuators
+                    .as_ref()
+                    .is_some_and(|actuators| actuators.contains(&Actuator::Linear))
+            });
+        if strokes {
+            spawn(stroke_all(args.clone(), clients.clone(), output.clone()));
+        }
+        spawn(watch_batteries(
+            args.clone(),
+            clients.clone(),
+            events.clone(),
+        ));
+        for ((server, client), client_events) in
+            args.server.iter().zip(clients.iter()).zip(client_events)
+        {
+            spawn(stay_connected_to_buttplug(
+                args.clone(),
+                server.clone(),
+                client.clone(),
+                clients.clone(),
+                client_events,
+                output.clone(),
+                events.clone(),
+            ));
+        }
+        info!("Connected to Intiface.");
+        // The dashboard reads keys itself.
+        if !args.tui {
+            spawn(pause_on_enter(
+                args.clone(),
+                clients.clone(),
+                output.clone(),
+            ));
+            info!("Press Enter to pause or resume vibration, or type `s` and press Enter for an emergency stop.");
+        }
+    }
+
+    let running_lldb: Arc<Mutex<Option<ChildShutdown>>> = Arc::new(Mutex::new(None));
+
+    let focus = args
+        .freeze_on_focus_loss
+        .then(|| focus::watch_focus(args.game.app_name()));
+
+    let mut tui = if args.tui {
+        Some(tui::spawn_tui(
+            args.clone(),
+            &events,
+            clients.clone(),
+            output.clone(),
+        )?)
+    } else {
+        None
+    };
+
+    // Attempts to attach to StarCraft since we last got APM from it.
+    let mut lldb_attempts = 0;
+    let session_end = args.max_session.map(|max_session| started + max_session);
+    loop {
+        select! {
+            signal_result = wait_for_quit(tui.as_ref()).fuse() => {
+                // This probably won't fail unless we can't install a Ctrl-C handler.
+                signal_result.map_err(|e| anyhow!(e))?;
+                break;
+            }
+            signal_result = wait_for_sigterm().fuse() => {
+                signal_result.map_err(|e| anyhow!(e).context("Couldn't listen for SIGTERM"))?;
+                info!("Got SIGTERM. Shutting down…");
+                break;
+            }
+            _ = wait_for_session_end(session_end).fuse() => {
+                info!(
+                    "Reached the max session length of {max_session}. Shutting down…",
+                    max_session = humantime::format_duration(args.max_session.unwrap_or_default())
+                );
+                break;
+            }
+            _ = wait_for_wake().fuse() => {
+                warn!("System woke up from sleep. Stopping vibrators and reconnecting to Intiface and StarCraft…");
+                restart_connections(&clients, &output, running_lldb.clone()).await;
+            }
+            _ = wait_for_silence(&events, args.watchdog.then_some(Duration::from_secs(args.watchdog_minutes * 60))).fuse() => {
+                error!(
+                    "Watchdog: nothing has happened for {minutes} minutes. \
+                    Stopping vibrators and reconnecting to Intiface and StarCraft in case something is stuck…",
+                    minutes = args.watchdog_minutes,
+                );
+                restart_connections(&clients, &output, running_lldb.clone()).await;
+            }
+            sync_result = async {
+                match args.pattern {
+                    Some(pattern) => {
+                        // Plays until we quit.
+                        pattern::play_pattern(&args, &clients, &output, pattern).await;
+                        Ok(())
+                    }
+                    None => sync_apm_to_vibrators(&args, &clients, running_lldb.clone(), output.clone(), events.clone(), external_apm.clone(), sample_tx.clone(), focus.clone(), &mut lldb_attempts).await,
+                }
+            }.fuse() => {
+                sync_result?;
+                info!("Lost connection to StarCraft.");
+                // Attempts are reset once APM arrives, so this skips attempts that never got any.
+                if args.once && lldb_attempts == 0 {
+                    break;
+                }
+                info!("Waiting for StarCraft to be relaunched…");
+            }
+        }
+    }
+
+    // Put the terminal back first so the rest of shutdown can be seen, and Ctrl-C works again.
+    if let Some(tui) = tui.take() {
+        tui.close();
+    }
+    // Held until we exit so nothing can start a device again.
+    // Stopping and disconnecting come before anything that can fail.
+    output.stop_workers();
+    let locked_clients = stop_all_vibrators_and_confirm(&clients).await;
+    for client in locked_clients.iter().filter(|client| client.connected()) {
+        if let Err(e) = client.disconnect().await {
+            warn!("Couldn't disconnect from Intiface: {e}");
+        }
+    }
+    if let Some(discord) = discord.take() {
+        discord.close().await;
+    }
+    if let Some(metrics) = metrics.take() {
+        metrics.close();
+    }
+    if let Some(lldb) = running_lldb.lock().await.take() {
+        info!("Waiting for lldb to terminate… (press Ctrl-C again to force quit)");
+        select! {
+            terminate_result = lldb.terminate().fuse() => {
+                terminate_result?;
+                info!("lldb terminated.");
+            }
+            // `terminate` has already sent lldb SIGKILL, so all that's left is to stop waiting.
+            _ = signal::ctrl_c().fuse() => {
+                warn!("Force quitting without waiting for lldb to terminate.");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Finishes at the end of the session set by `--max-session`, or never if there isn't one.
+async fn wait_for_session_end(session_end: Option<Instant>) {
+    match session_end {
+        Some(session_end) => sleep_until(session_end.into()).await,
+        None => pending().await,
+    }
+}
+
+/// Wait for Ctrl-C, or for the user to quit from the dashboard.
+async fn wait_for_quit(tui: Option<&tui::Tui>) -> std::io::Result<()> {
+    match tui {
+        Some(tui) => {
+            select! {
+                signal_result = signal::ctrl_c().fuse() => signal_result,
+                _ = tui.quit_requested().fuse() => Ok(()),
+            }
+        }
+        None => signal::ctrl_c().await,
+    }
+}
+
+/// Wait for SIGTERM, which launchd and wrapper scripts send to ask us to stop.
+#[cfg(unix)]
+async fn wait_for_sigterm() -> std::io::Result<()> {
+    signal::unix::signal(signal::unix::SignalKind::terminate())?
+        .recv()
+        .await;
+    Ok(())
+}
+
+/// There's no SIGTERM to wait for.
+#[cfg(not(unix))]
+async fn wait_for_sigterm() -> std::io::Result<()> {
+    pending().await
+}
+
+/// How often to check whether the system has been asleep.
+const SLEEP_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// If the wall clock gets this far ahead of the timer between checks, assume the system was asleep.
+const SLEEP_THRESHOLD: Duration = Duration::from_secs(10);
+
+/// Wait until the system wakes up from sleep.
+/// Tokio's timers use a clock that stops while the system is asleep, but the wall clock doesn't.
+async fn wait_for_wake() {
+    loop {
+        let before = SystemTime::now();
+        sleep(SLEEP_CHECK_INTERVAL).await;
+        let elapsed = SystemTime::now().duration_since(before).unwrap_or_default();
+        if elapsed >= SLEEP_CHECK_INTERVAL + SLEEP_THRESHOLD {
+            return;
+        }
+    }
+}
+
+/// Wait until there haven't been any events for this long.
+/// Never finishes if there's no window.
+async fn wait_for_silence(events: &Events, window: Option<Duration>) {
+    let Some(window) = window else {
+        return pending().await;
+    };
+    let mut rx = events.subscribe();
+    while let Ok(received) = timeout(window, rx.recv()).await {
+        if let Err(RecvError::Closed) = received {
+            return pending().await;
+        }
+    }
+}
+
+/// Stop vibrators, then drop our connections to StarCraft and Intiface so they get reestablished.
+async fn restart_connections(
+    clients: &[Arc<Mutex<ButtplugClient>>],
+    output: &Output,
+    running_lldb: Arc<Mutex<Option<ChildShutdown>>>,
+) {
+    stop_all_vibrators(clients, output).await;
+    // The main loop will start lldb again.
+    if let Some(lldb) = running_lldb.lock().await.take() {
+        if let Err(e) = lldb.terminate().await {
+            error!("Couldn't terminate lldb: {e}");
+        }
+    }
+    // The reconnection tasks will notice the disconnects and reconnect.
+    for client in clients {
+        let client = client.lock().await;
+        if client.connected() {
+            if let Err(e) = client.disconnect().await {
+                warn!("Couldn't disconnect from Intiface: {e}");
+            }
+        }
+    }
+}
+
+async fn check_prereqs() -> Result<()> {
+    let exit_status = Command::new("lldb")
+        .arg("--version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| anyhow!(e).context("Couldn't run `lldb --version`. Make sure it's installed by running `xcode-select --install`."))?
+        .wait()
+        .await?;
+    if !exit_status.success() {
+        bail!("`lldb --version` failed with status {exit_status}. Make sure it's installed by running `xcode-select --install`.");
+    }
+    Ok(())
+}
+
+/// Connect to an Intiface server, or start the embedded one with `--embedded`.
+async fn connect_to_buttplug(
+    args: &Args,
+    server: &str,
+    client: &Arc<Mutex<ButtplugClient>>,
+    events: Events,
+) -> Result<()> {
+    if args.embedded {
+        client
+            .lock()
+            .await
+            .connect(embedded::embedded_connector()?)
+            .await
+            .map_err(|e| anyhow!(e).context("Couldn't connect to the embedded Buttplug server."))?;
+        info!("Started the embedded Buttplug server.");
+    } else {
+        connect_to_intiface(args, server, client).await?;
+    }
+    events.emit(Event::IntifaceConnected {
+        server: server.to_owned(),
+    });
+    client
+        .lock()
+        .await
+        .start_scanning()
+        .await
+        .map_err(|e| anyhow!(e).context("Couldn't start scanning for vibrators."))?;
+    if args.scan_seconds > 0 {
+        spawn(stop_scanning_after(
+            client.clone(),
+            server.to_owned(),
+            Duration::from_secs(args.scan_seconds),
+        ));
+    }
+    Ok(())
+}
+
+/// Connect to Intiface at `server`, retrying until it answers.
+async fn connect_to_intiface(
+    args: &Args,
+    server: &str,
+    client: &Arc<Mutex<ButtplugClient>>,
+) -> Result<()> {
+    let tls = uses_tls(server)?;
+    let mut checked_for_intiface = false;
+    loop {
+        let transport = if tls {
+            ButtplugWebsocketClientTransport::new_secure_connector(server, args.allow_invalid_certs)
+        } else {
+            ButtplugWebsocketClientTransport::new_insecure_connector(server)
+        };
+        let Err(e) = client
+            .lock()
+            .await
+            .connect(ButtplugRemoteClientConnector::<
+                ButtplugWebsocketClientTransport,
+                ButtplugClientJSONSerializer,
+            >::new(transport))
+            .await
+        else {
+            break;
+        };
+        warn!("Couldn't connect to Intiface: {e}");
+        if !checked_for_intiface && is_local_server(server) {
+            checked_for_intiface = true;
+            if find_pid_by_exe_name(&args.intiface_process_name).is_none() {
+                warn!(
+                    "Intiface Central doesn't appear to be running. \
+                    Please start it and press its ▶️ button to start its server."
+                );
+            }
+        }
+        info!("Please make sure the Intiface server is running and listening at {server}. Waiting {wait:?} and trying again…", wait = args.reconnect_interval);
+        sleep(args.reconnect_interval).await;
+    }
+    info!("Connected to Intiface at {server}.");
+    Ok(())
+}
+
+/// How long to scan for a device that disconnected, with `--reconnect-devices`.
+const DEVICE_RECONNECT_WINDOW: Duration = Duration::from_secs(60);
+
+/// Stop looking for new devices after a while.
+/// Devices that were already found can still disconnect and reconnect.
+async fn stop_scanning_after(client: Arc<Mutex<ButtplugClient>>, server: String, wait: Duration) {
+    sleep(wait).await;
+    let client = client.lock().await;
+    if !client.connected() {
+        return;
+    }
+    match client.stop_scanning().await {
+        Ok(()) => info!(
+            "Stopped scanning for devices on {server}. Found {count}.",
+            count = client.devices().len()
+        ),
+        Err(e) => warn!("Couldn't stop scanning for devices on {server}: {e}"),
+    }
+}
+
+/// Where the subcommand is in these arguments, not counting the program name, if there is one.
+/// It's the first argument that isn't an option or an option's value.
+fn subcommand_index(command: &clap::Command, args: &[OsString]) -> Option<usize> {
+    let mut args = args.iter().enumerate();
+    while let Some((index, arg)) = args.next() {
+        let arg = arg.to_string_lossy();
+        if arg == "--" {
+            return None;
+        }
+        let Some(long) = arg.strip_prefix("--") else {
+            return Some(index);
+        };
+        if long.contains('=') {
+            continue;
+        }
+        let takes_value = command
+            .get_arguments()
+            .find(|option| option.get_long() == Some(long))
+            .is_some_and(|option| option.get_action().takes_values());
+        if takes_value {
+            args.next();
+        }
+    }
+    None
+}
+
+/// Does this Intiface server URL need TLS?
+/// Fails for anything other than `ws://` and `wss://` URLs.
+fn uses_tls(server: &str) -> Result<bool> {
+    match server
+        .split_once("://")
+        .map(|(scheme, _)| scheme.to_ascii_lowercase())
+    {
+        Some(scheme) if scheme == "ws" => Ok(false),
+        Some(scheme) if scheme == "wss" => Ok(true),
+        _ => bail!("Intiface server URL must start with ws:// or wss://, but it's {server}"),
+    }
+}
+
+/// Port Intiface Central listens on by default.
+const DEFAULT_INTIFACE_PORT: u16 = 12345;
+
+/// Check an Intiface server URL, filling in the default port and dropping trailing slashes.
+/// URLs that can't be fixed that way are an error that suggests a fix, if there is one.
+fn normalize_server(server: &str) -> Result<String> {
+    let trimmed = server.trim().trim_end_matches('/');
+    let Some((scheme, rest)) = trimmed.split_once("://") else {
+        bail!("{server} is missing a ws:// or wss:// scheme. Did you mean ws://{trimmed}?");
+    };
+    let scheme = scheme.to_ascii_lowercase();
+    match scheme.as_str() {
+        "ws" | "wss" => {}
+        "http" => bail!("Intiface uses websockets, not HTTP. Did you mean ws://{rest}?"),
+        "https" => bail!("Intiface uses websockets, not HTTPS. Did you mean wss://{rest}?"),
+        _ => bail!("{server} must start with ws:// or wss://. Did you mean ws://{rest}?"),
+    }
+    let (authority, path) = match rest.split_once('/') {
+        Some((authority, path)) => (authority, format!("/{path}")),
+        None => (rest, String::new()),
+    };
+    let (host, port) = match authority.strip_prefix('[') {
+        Some(ipv6) => {
+            let Some((host, after)) = ipv6.split_once(']') else {
+                bail!("{server} has an unclosed [ in its IPv6 address");
+            };
+            (format!("[{host}]"), after.strip_prefix(':'))
+        }
+        None => match authority.rsplit_once(':') {
+            Some((host, port)) => (host.to_owned(), Some(port)),
+            None => (authority.to_owned(), None),
+        },
+    };
+    if host.is_empty() || host == "[]" {
+        bail!("{server} is missing a host. Did you mean {scheme}://localhost:{DEFAULT_INTIFACE_PORT}?");
+    }
+    let port = match port {
+        Some(port) => port
+            .parse::<u16>()
+            .map_err(|_| anyhow!("{server} has an invalid port {port:?}"))?,
+        None => DEFAULT_INTIFACE_PORT,
+    };
+    Ok(format!("{scheme}://{host}:{port}{path}"))
+}
+
+/// Does this server URL point at this machine?
+fn is_local_server(server: &str) -> bool {
+    let authority = server.split_once("://").map_or(server, |(_, rest)| rest);
+    let host = match authority.strip_prefix('[') {
+        Some(ipv6) => ipv6.split(']').next().unwrap_or_default(),
+        None => authority.split([':', '/']).next().unwrap_or_default(),
+    };
+    matches!(host, "localhost" | "127.0.0.1" | "::1")
+}
+
+async fn stay_connected_to_buttplug(
+    args: Arc<Args>,
+    server: String,
+    client: Arc<Mutex<ButtplugClient>>,
+    clients: Clients,
+    mut client_events: impl Stream<Item = ButtplugClientEvent> + Unpin,
+    output: Output,
+    events: Events,
+) {
+    // Devices that disconnected and that we're scanning for, for `--reconnect-devices`.
+    let mut missing_devices: HashSet<String> = HashSet::new();
+    // Stops the scan for missing devices. Only one runs at a time.
+    let mut rescan: Option<JoinHandle<()>> = None;
+    while let Some(event) = client_events.next().await {
+        match event {
+            ButtplugClientEvent::ServerDisconnect => {
+                warn!("Disconnected from Intiface at {server}. Vibration disabled. Attempting to reconnect…");
+                events.emit(Event::IntifaceDisconnected {
+                    server: server.clone(),
+                });
+                if let Some(rescan) = rescan.take() {
+                    rescan.abort();
+                }
+                missing_devices.clear();
+                if let Err(e) = connect_to_buttplug(&args, &server, &client, events.clone()).await {
+                    error!("Error while reconnecting to Intiface at {server}: {e}");
+                }
+                info!("Reconnected to Intiface at {server}. Vibration enabled.");
+                // APM may have changed while we were disconnected, and won't be sent again until it changes.
+                let level = output.get();
+                if level > 0.0 {
+                    vibrate_all(&args, &clients, &output, level).await;
+                }
+            }
+            ButtplugClientEvent::Error(e) => {
+                error!("Intiface client error: {e}");
+            }
+            ButtplugClientEvent::PingTimeout => {
+                error!("Intiface client ping timeout!");
+            }
+            ButtplugClientEvent::DeviceAdded(device) => {
+                log_device_added(&args, &device);
+                output.enable_device(device.name());
+                events.emit(Event::DeviceAdded {
+                    server: server.clone(),
+                    name: device.name().clone(),
+                    driven: args.drives(&device),
+                });
+                if missing_devices.remove(device.name()) {
+                    info!("{name} is back.", name = device.name());
+                    if missing_devices.is_empty() {
+                        if let Some(rescan) = rescan.take() {
+                            rescan.abort();
+                            stop_scanning_after(client.clone(), server.clone(), Duration::ZERO)
+                                .await;
+                        }
+                    }
+                }
+                // Bring the new device up to the current level instead of waiting for APM to change.
+                let level = output.get();
+                if level > 0.0 {
+                    vibrate_all(&args, &clients, &output, level).await;
+                }
+            }
+            ButtplugClientEvent::DeviceRemoved(device) => {
+                info!("Device disconnected: {name}", name = device.name());
+                events.emit(Event::DeviceRemoved {
+                    server: server.clone(),
+                    name: device.name().clone(),
+                });
+                if args.reconnect_devices {
+                    missing_devices.insert(device.name().clone());
+                    // With `--scan-seconds 0`, we never stopped scanning, so the device will turn up by itself.
+                    if args.scan_seconds > 0 {
+                        if let Err(e) = client.lock().await.start_scanning().await {
+                            warn!("Couldn't scan for {name} again: {e}", name = device.name());
+                            continue;
+                        }
+                        info!(
+                            "Scanning for {name} for {wait:?}…",
+                            name = device.name(),
+                            wait = DEVICE_RECONNECT_WINDOW
+                        );
+                        // Replace any scan that's already running, so repeated drops only ever leave one.
+                        if let Some(rescan) = rescan.take() {
+                            rescan.abort();
+                        }
+                        rescan = Some(spawn(stop_scanning_after(
+                            client.clone(),
+                            server.clone(),
+                            DEVICE_RECONNECT_WINDOW,
+                        )));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+trait ButtplugClientDeviceExt {
+    fn is_vibrator(&self) -> bool;
+    fn is_rotator(&self) -> bool;
+    fn is_oscillator(&self) -> bool;
+    fn is_constrictor(&self) -> bool;
+    fn scalar_cmd_for(
+        &self,
+        actuator_type: ActuatorType,
+        level: f64,
+        skip: &[u32],
+    ) -> ScalarCommand;
+    fn vibrate_cmd_for(&self, level: f64, actuator_map: &[ActuatorMapping]) -> ScalarCommand;
+    fn is_stroker(&self) -> bool;
+    fn actuator_names(&self) -> Vec<String>;
+    fn has_actuator(&self, actuator: Actuator) -> bool;
+    fn oscillators_sharing_vibrator_motors(&self) -> Vec<u32>;
+}
+
+/// Indexes of the `skipped` actuators that share a motor with a `preferred` actuator,
+/// going by their feature descriptors. Actuators without a descriptor never match.
+fn shared_motor_indexes(
+    actuators: &[(String, ActuatorType)],
+    preferred: ActuatorType,
+    skipped: ActuatorType,
+) -> Vec<u32> {
+    let known = |descriptor: &str| !descriptor.is_empty() && descriptor != "N/A";
+    actuators
+        .iter()
+        .enumerate()
+        .filter(|(_, (descriptor, actuator_type))| {
+            *actuator_type == skipped
+                && known(descriptor)
+                && actuators
+                    .iter()
+                    .any(|(other, other_type)| *other_type == preferred && other == descriptor)
+        })
+        .map(|(index, _)| index as u32)
+        .collect()
+}
+
+impl ButtplugClientDeviceExt for ButtplugClientDevice {
+    fn is_vibrator(&self) -> bool {
+        if let Some(scalar_cmds) = self.message_attributes().scalar_cmd() {
+            return scalar_cmds
+                .iter()
+                .find(|scalar_cmd| *scalar_cmd.actuator_type() == ActuatorType::Vibrate)
+                .is_some();
+        }
+        false
+    }
+
+    fn is_rotator(&self) -> bool {
+        self.message_attributes().rotate_cmd().is_some()
+    }
+
+    fn is_oscillator(&self) -> bool {
+        self.message_attributes()
+            .scalar_cmd()
+            .as_ref()
+            .is_some_and(|scalar_cmds| {
+                scalar_cmds
+                    .iter()
+                    .any(|scalar_cmd| *scalar_cmd.actuator_type() == ActuatorType::Oscillate)
+            })
+    }
+
+    fn is_constrictor(&self) -> bool {
+        self.message_attributes()
+            .scalar_cmd()
+            .as_ref()
+            .is_some_and(|scalar_cmds| {
+                scalar_cmds
+                    .iter()
+                    .any(|scalar_cmd| *scalar_cmd.actuator_type() == ActuatorType::Constrict)
+            })
+    }
+
+    /// Set every actuator of one type to the same level, leaving the device's other actuators alone.
+    /// Unlike `VibrateCommand::Speed`, this uses the actuators' real indexes,
+    /// so it works on devices that mix vibration with other actuator types.
+    /// Actuators whose indexes are in `skip` are left alone too.
+    fn scalar_cmd_for(
+        &self,
+        actuator_type: ActuatorType,
+        level: f64,
+        skip: &[u32],
+    ) -> ScalarCommand {
+        ScalarCommand::ScalarMap(
+            self.message_attributes()
+                .scalar_cmd()
+                .iter()
+                .flatten()
+                .enumerate()
+                .filter(|(index, scalar_cmd)| {
+                    *scalar_cmd.actuator_type() == actuator_type && !skip.contains(&(*index as u32))
+                })
+                .map(|(index, _)| (index as u32, (level, actuator_type)))
+                .collect(),
+        )
+    }
+
+    /// Set the device's vibration motors, following `--actuator-map`.
+    fn vibrate_cmd_for(&self, level: f64, actuator_map: &[ActuatorMapping]) -> ScalarCommand {
+        match self.scalar_cmd_for(ActuatorType::Vibrate, level, &[]) {
+            ScalarCommand::ScalarMap(levels) => ScalarCommand::ScalarMap(
+                levels
+                    .into_iter()
+                    .map(|(index, (level, actuator_type))| {
+                        let level = mapper::actuator_level(actuator_map, index, level);
+                        (index, (level, actuator_type))
+                    })
+                    .collect(),
+            ),
+            command => command,
+        }
+    }
+
+    fn is_stroker(&self) -> bool {
+        self.message_attributes().linear_cmd().is_some()
+    }
+
+    /// Every kind of actuator the device has, whether or not StarPlug can drive it.
+    fn actuator_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = vec![];
+        for scalar_cmd in self.message_attributes().scalar_cmd().iter().flatten() {
+            let name = scalar_cmd.actuator_type().to_string().to_lowercase();
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+        if self.is_rotator() {
+            names.push("rotate".to_owned());
+        }
+        if self.is_stroker() {
+            names.push("linear".to_owned());
+        }
+        names
+    }
+
+    fn has_actuator(&self, actuator: Actuator) -> bool {
+        match actuator {
+            Actuator::Vibrate => self.is_vibrator(),
+            Actuator::Rotate => self.is_rotator(),
+            Actuator::Oscillate => self.is_oscillator(),
+            Actuator::Constrict => self.is_constrictor(),
+            Actuator::Linear => self.is_stroker(),
+        }
+    }
+
+    /// Oscillate actuators that are really one of the device's vibration motors, listed again.
+    fn oscillators_sharing_vibrator_motors(&self) -> Vec<u32> {
+        let actuators: Vec<(String, ActuatorType)> = self
+            .message_attributes()
+            .scalar_cmd()
+            .iter()
+            .flatten()
+            .map(|scalar_cmd| {
+                (
+                    scalar_cmd.feature_descriptor().clone(),
+                    *scalar_cmd.actuator_type(),
+                )
+            })
+            .collect();
+        shared_motor_indexes(&actuators, ActuatorType::Vibrate, ActuatorType::Oscillate)
+    }
+}
+
+/// Log a newly found device's actuators, and which of them StarPlug will drive.
+fn log_device_added(args: &Args, device: &ButtplugClientDevice) {
+    let name = device.name();
+    let actuator_names = device.actuator_names();
+    if actuator_names.is_empty() {
+        info!("Device connected: {name}, with no actuators. StarPlug won't drive it.");
+        return;
+    }
+    info!(
+        "Device connected: {name}, with actuators: {actuators}",
+        actuators = actuator_names.join(", ")
+    );
+    if !args.allows_device(name) {
+        info!("StarPlug won't drive {name}, because of --device-name-regex or --device-name-regex-deny.");
+        return;
+    }
+    let given_scale = args
+        .device_scale
+        .iter()
+        .any(|device_scale| device_scale.name == *name);
+    if !given_scale
+        && args
+            .remembered_device_scale
+            .iter()
+            .any(|device_scale| device_scale.name == *name)
+    {
+        info!(
+            "Using the scale of {scale} that {name} was given last time.",
+            scale = args.device_scale(name)
+        );
+    }
+    if let Some(band) = args.device_band(name) {
+        info!(
+            "{name} will follow APM from {min} to {max}, because of --device-band.",
+            min = band.min_apm,
+            max = band.max_apm
+        );
+    }
+    if let Some(entry) = args.device_entry(name) {
+        check_device_settings(device, entry);
+    }
+    let actuator_name = |actuator: &Actuator| {
+        actuator
+            .to_possible_value()
+            .map(|value| value.get_name().to_owned())
+            .unwrap_or_default()
+    };
+    let driven: Vec<String> = args
+        .device_actuators(name)
+        .iter()
+        .filter(|actuator| device.has_actuator(**actuator))
+        .map(actuator_name)
+        .collect();
+    if !driven.is_empty() {
+        info!(
+            "StarPlug will drive {name}'s {actuators} actuators.",
+            actuators = driven.join(", ")
+        );
+        return;
+    }
+    let usable: Vec<String> = Actuator::value_variants()
+        .iter()
+        .filter(|actuator| device.has_actuator(**actuator))
+        .map(actuator_name)
+        .collect();
+    if usable.is_empty() {
+        info!("StarPlug won't drive {name}, because it can't use any of its actuators.");
+    } else {
+        info!(
+            "StarPlug won't drive {name}. Add {actuators} to --actuators to drive it.",
+            actuators = usable.join(", ")
+        );
+    }
+}
+
+/// Warn about anything in a device's `[devices]` entry that the device doesn't have.
+fn check_device_settings(device: &ButtplugClientDevice, entry: &DeviceSettings) {
+    let name = device.name();
+    for actuator in entry.actuators.iter().flatten() {
+        if !device.has_actuator(*actuator) {
+            warn!(
+                "{name} has no {actuator} actuators, though its [devices] entry in the config file lists them.",
+                actuator = actuator
+                    .to_possible_value()
+                    .map(|value| value.get_name().to_owned())
+                    .unwrap_or_default()
+            );
+        }
+    }
+    let vibrators: Vec<u32> = device
+        .message_attributes()
+        .scalar_cmd()
+        .iter()
+        .flatten()
+        .enumerate()
+        .filter(|(_, scalar_cmd)| *scalar_cmd.actuator_type() == ActuatorType::Vibrate)
+        .map(|(index, _)| index as u32)
+        .collect();
+    for mapping in entry.actuator_map.iter().flatten() {
+        if !vibrators.contains(&mapping.index) {
+            warn!(
+                "{name} has no vibration motor {index}, though its [devices] entry in the config file maps it. Its vibration motors are {vibrators:?}.",
+                index = mapping.index
+            );
+        }
+    }
+}
+
+/// How long to wait before the second attempt to attach to StarCraft.
+/// Doubles with each failed attempt after that.
+const LLDB_RETRY_WAIT: Duration = Duration::from_secs(1);
+
+/// Longest wait between attempts to attach to StarCraft.
+const LLDB_MAX_RETRY_WAIT: Duration = Duration::from_secs(30);
+
+/// How long to wait before attaching to StarCraft again, after some attempts that didn't get APM.
+fn lldb_retry_wait(attempts: u32) -> Duration {
+    if attempts == 0 {
+        return Duration::ZERO;
+    }
+    LLDB_RETRY_WAIT
+        .saturating_mul(1 << (attempts - 1).min(16))
+        .min(LLDB_MAX_RETRY_WAIT)
+}
+
+/// Monitor StarCraft.
+/// Send vibration commands when APM changes.
+/// Stop all vibrators if we don't get an APM change for a while.
+#[allow(clippy::too_many_arguments)]
+async fn sync_apm_to_vibrators(
+    args: &Args,
+    clients: &[Arc<Mutex<ButtplugClient>>],
+    running_lldb: Arc<Mutex<Option<ChildShutdown>>>,
+    output: Output,
+    events: Events,
+    external_apm: Option<watch::Receiver<Status>>,
+    samples: Option<mpsc::Sender<i32>>,
+    focus: Option<watch::Receiver<bool>>,
+    lldb_attempts: &mut u32,
+) -> Result<()> {
+    let mut apm_rx = match external_apm {
+        Some(apm_rx) => apm_rx,
+        None => {
+            let wait = lldb_retry_wait(*lldb_attempts);
+            if !wait.is_zero() {
+                info!(
+                    "Waiting {wait:?} before attaching to StarCraft again (attempt {attempt})…",
+                    attempt = *lldb_attempts + 1
+                );
+                sleep(wait).await;
+            }
+            *lldb_attempts += 1;
+            info!("Starting lldb…");
+            let Some(apm_rx) = connect_to_starcraft(args, running_lldb.clone(), samples).await?
+            else {
+                // The main loop will try again.
+                return Ok(());
+            };
+            info!("lldb started.");
+            apm_rx
+        }
+    };
+
+    let mapper = ApmMapper::new(args);
+    let mut game_running = false;
+    let mut session_apm = RollingApm::new(SESSION_APM_WINDOW);
+    let mut session_active = true;
+    // Highest APM milestone reached in the current game.
+    let mut last_milestone = 0;
+    let mut ramp = Ramp::from_args(args);
+    let mut target_level = 0.0;
+    let mut last_apm_change = Instant::now();
+    let mut armed = args.arm_delay == 0;
+    let mut arm_at: Option<Instant> = None;
+    let mut has_focus = true;
+    let mut samples_to_skip = args.skip_first_samples;
+    // When we got an APM of zero that we're ignoring for now, thanks to `--zero-hold-ms`.
+    let mut held_zero_since: Option<Instant> = None;
+    let zero_hold = Duration::from_millis(args.zero_hold_ms);
+    // Only lldb is attached to a StarCraft process that can exit.
+    let watch_process =
+        args.apm_source == ApmSource::Lldb && args.game == Game::Sc1 && args.replay.is_none();
+    // Whether StarCraft has sent anything since we attached, so it's been running at some point.
+    let mut received_apm = false;
+    // Last time the level was high enough to not count as idle, for `--idle-timeout`.
+    let mut last_active_level = Instant::now();
+    let mut idle = false;
+    let mut deadzone = Deadzone::new(args.min_apm, args.deadzone);
+    let mut fade: Option<FadeOut> = None;
+    let mut soft_start: Option<SoftStart> = None;
+    // Keyboard APM is zero whenever you're not typing, so a run of zeros doesn't mean anything's wrong.
+    let mut zero_apm = (!args.apm_from_keyboard()).then(ZeroApmWatch::default);
+    loop {
+        let vibrating = game_running && session_active && has_focus && !idle;
+        let soft_starting = vibrating && soft_start.as_ref().is_some_and(SoftStart::is_running);
+        let ramping = vibrating
+            && ramp
+                .as_ref()
+                .is_some_and(|ramp| ramp.is_moving(target_level));
+        let mut wait = if ramping {
+            RAMP_TICK
+        } else {
+            args.game_timeout.saturating_sub(last_apm_change.elapsed())
+        };
+        if let Some(held_zero_since) = held_zero_since {
+            wait = wait.min(zero_hold.saturating_sub(held_zero_since.elapsed()));
+        }
+        if fade.is_some() || soft_starting {
+            wait = wait.min(RAMP_TICK);
+        }
+
+        let mut zero_hold_expired = false;
+        let apm = match timeout(wait, apm_rx.changed()).await {
+            Ok(Ok(())) => {
+                let Status::Apm(apm) = *apm_rx.borrow_and_update() else {
+                    // No need to wait for APM to go quiet.
+                    held_zero_since = None;
+                    if game_running {
+                        info!("Game over. Stopping vibration.");
+                        game_running = false;
+                        events.emit(Event::GameStopped);
+                        fade = None;
+                        soft_start = None;
+                        stop_all_vibrators(clients, &output).await;
+                        if let Some(ramp) = &mut ramp {
+                            ramp.reset();
+                        }
+                    }
+                    continue;
+                };
+                last_apm_change = Instant::now();
+                received_apm = true;
+                match zero_apm
+                    .as_mut()
+                    .and_then(|zero_apm| zero_apm.observe_at(apm, last_apm_change))
+                {
+                    Some(ZeroApmNotice::LongRun) => warn_about_zero_apm(args),
+                    Some(ZeroApmNotice::Recovered) => info!("APM is no longer zero."),
+                    None => {}
+                }
+                if fade.take().is_some() {
+                    info!("APM changed while fading out. Resuming vibration.");
+                }
+                apm
+            }
+            Ok(Err(e)) => {
+                error!("APM channel closed: {e}");
+                if game_running {
+                    events.emit(Event::GameStopped);
+                }
+                stop_all_vibrators(clients, &output).await;
+                if args.replay.is_some() {
+                    bail!("Replay stopped.");
+                }
+                if args.apm_from_keyboard() {
+                    // Unlike lldb, we can't restart keyboard monitoring.
+                    bail!("Keyboard monitoring stopped.");
+                }
+                return Ok(());
+            }
+            Err(_) if fade.is_some() => {
+                let level = fade.as_ref().map_or(0.0, FadeOut::level);
+                if level > 0.0 {
+                    vibrate_all(args, clients, &output, level).await;
+                } else {
+                    fade = None;
+                    stop_all_vibrators(clients, &output).await;
+                }
+                continue;
+            }
+            Err(_) if held_zero_since.is_some_and(|since| since.elapsed() >= zero_hold) => {
+                // APM has stayed at zero for the whole hold window, so act on it now.
+                held_zero_since = None;
+                zero_hold_expired = true;
+                0
+            }
+            Err(_) if ramping && last_apm_change.elapsed() < args.game_timeout => {
+                if let Some(ramp) = &mut ramp {
+                    let level = soft_start_level(&mut soft_start, ramp.update(target_level));
+                    vibrate_all(args, clients, &output, level).await;
+                }
+                continue;
+            }
+            Err(_) if soft_starting && last_apm_change.elapsed() < args.game_timeout => {
+                let level = ramp.as_ref().map_or(target_level, |ramp| ramp.level);
+                let level = soft_start_level(&mut soft_start, level);
+                vibrate_all(args, clients, &output, level).await;
+                continue;
+            }
+            Err(_) => {
+                // Start a fresh wait for the next APM change.
+                last_apm_change = Instant::now();
+                held_zero_since = None;
+                // APM freezes both when StarCraft is paused and when it's gone, so look for the process.
+                if watch_process && received_apm && !starcraft_is_running(args) {
+                    info!("StarCraft exited.");
+                    if game_running {
+                        events.emit(Event::GameStopped);
+                    }
+                    stop_all_vibrators(clients, &output).await;
+                    // Don't wait for lldb to notice. The main loop will start it again.
+                    if let Some(lldb) = running_lldb.lock().await.take() {
+                        if let Err(e) = lldb.terminate().await {
+                            error!("Couldn't terminate lldb: {e}");
+                        }
+                    }
+                    return Ok(());
+                }
+                if game_running {
+                    if watch_process {
+                        info!(
+                            "APM hasn't changed in a while, but StarCraft is still running. \
+                            The game appears paused, or it finished."
+                        );
+                    } else {
+                        info!(
+                            "APM hasn't changed in a while. \
+                            The current game may have finished or StarCraft may be paused."
+                        );
+                    }
+                    game_running = false;
+                    events.emit(Event::GameStopped);
+                    soft_start = None;
+                    match args.fade_out_seconds {
+                        Some(seconds) if output.get() > 0.0 => {
+                            info!("Fading out vibration over {seconds} seconds.");
+                            fade = Some(FadeOut::new(output.get(), seconds));
+                        }
+                        _ => stop_all_vibrators(clients, &output).await,
+                    }
+                    if let Some(ramp) = &mut ramp {
+                        ramp.reset();
+                    }
+                }
+                continue;
+            }
+        };
+
+        if samples_to_skip > 0 {
+            samples_to_skip -= 1;
+            info!(
+                "Ignoring {rate} from right after connecting to StarCraft.",
+                rate = args.display_units.format(apm as f64)
+            );
+            continue;
+        }
+        if !game_running {
+            info!("Connected to StarCraft: received first APM change.");
+            *lldb_attempts = 0;
+            game_running = true;
+            events.emit(Event::GameStarted);
+            session_apm = RollingApm::new(SESSION_APM_WINDOW);
+            session_active = true;
+            last_milestone = 0;
+            last_active_level = Instant::now();
+            idle = false;
+            deadzone.reset();
+            soft_start = args.soft_start_seconds.map(SoftStart::new);
+            if !armed && arm_at.is_none() {
+                arm_at = Some(Instant::now() + Duration::from_secs(args.arm_delay));
+                info!(
+                    "Vibration will start in {delay} seconds.",
+                    delay = args.arm_delay
+                );
+            }
+        }
+        if !armed && arm_at.is_some_and(|arm_at| Instant::now() >= arm_at) {
+            armed = true;
+            info!("Armed: vibration enabled.");
+        }
+        if apm != 0 {
+            held_zero_since = None;
+        } else if !zero_hold.is_zero() && !zero_hold_expired {
+            // Keep the previous level until we're sure this isn't a glitch.
+            held_zero_since.get_or_insert_with(Instant::now);
+            continue;
+        }
+        if let Some(min_session_apm) = args.min_session_apm {
+            if let Some(average) = session_apm.push(apm) {
+                let active = average >= min_session_apm as f64;
+                if active != session_active {
+                    session_active = active;
+                    let average = args.display_units.format(average);
+                    let min_session_apm = args.display_units.format(min_session_apm as f64);
+                    if active {
+                        info!("Average {average} is back above {min_session_apm}. Resuming vibration.");
+                    } else {
+                        info!(
+                            "Average {average} has stayed below {min_session_apm}. \
+                            You may be AFK. Stopping vibration until you pick the pace back up."
+                        );
+                        stop_all_vibrators(clients, &output).await;
+                        if let Some(ramp) = &mut ramp {
+                            ramp.reset();
+                        }
+                    }
+                }
+            }
+            if !session_active {
+                continue;
+            }
+        }
+        if !armed {
+            let level = mapper.level(apm);
+            info!(
+                "{rate} → {percent}% (not armed yet)",
+                percent = (level * 100.0).round(),
+                rate = args.display_units.format(apm as f64)
+            );
+            events.emit(Event::Apm { apm, level });
+            continue;
+        }
+        if let Some(focused_rx) = &focus {
+            let focused = *focused_rx.borrow();
+            if focused != has_focus {
+                has_focus = focused;
+                if focused {
+                    info!("StarCraft is frontmost again. Resuming vibration.");
+                } else {
+                    info!("StarCraft isn't frontmost. Pausing vibration until it is.");
+                    stop_all_vibrators(clients, &output).await;
+                    if let Some(ramp) = &mut ramp {
+                        ramp.reset();
+                    }
+                }
+            }
+            if !has_focus {
+                continue;
+            }
+        }
+        if let Some(step) = args.milestone_step {
+            let milestone = apm / step;
+            if milestone > last_milestone {
+                last_milestone = milestone;
+                info!(
+                    "Reached {rate} milestone!",
+                    rate = args.display_units.format((milestone * step) as f64)
+                );
+                // Banded devices get the pulse too.
+                vibrate_all_with_apm(args, clients, &output, args.milestone_level, None).await;
+                sleep(Duration::from_millis(args.milestone_ms)).await;
+            }
+        }
+        target_level = mapper.level(deadzone.update(apm));
+        if target_level >= IDLE_LEVEL {
+            last_active_level = Instant::now();
+            if idle {
+                idle = false;
+                info!("APM picked back up. Resuming vibration.");
+            }
+        } else if let Some(idle_timeout) = args.idle_timeout {
+            if !idle && last_active_level.elapsed() >= Duration::from_secs(idle_timeout) {
+                idle = true;
+                info!(
+                    "Vibration level has stayed below {IDLE_LEVEL} for {idle_timeout} seconds. \
+                    Stopping vibration until APM picks back up."
+                );
+                stop_all_vibrators(clients, &output).await;
+                if let Some(ramp) = &mut ramp {
+                    ramp.reset();
+                }
+            }
+        }
+        if idle {
+            continue;
+        }
+        let level = match &mut ramp {
+            Some(ramp) => ramp.update(target_level),
+            None => target_level,
+        };
+        let level = soft_start_level(&mut soft_start, level);
+        apm_changed(args, apm, level, clients, &output, &events).await;
+    }
+}
+
+/// Levels below this count as idle for `--idle-timeout`.
+const IDLE_LEVEL: f64 = 0.05;
+
+/// How often to update the vibration level while it's ramping.
+const RAMP_TICK: Duration = Duration::from_millis(50);
+
+/// Vibration fading out after a game ends, for `--fade-out-seconds`.
+struct FadeOut {
+    from: f64,
+    duration: Duration,
+    started: Instant,
+}
+
+impl FadeOut {
+    fn new(from: f64, seconds: f64) -> Self {
+        Self {
+            from,
+            duration: Duration::from_secs_f64(seconds),
+            started: Instant::now(),
+        }
+    }
+
+    /// Current level of the fade. Zero once it's over.
+    fn level(&self) -> f64 {
+        self.level_at(Instant::now())
+    }
+
+    /// [`FadeOut::level`] at `now`.
+    fn level_at(&self, now: Instant) -> f64 {
+        let progress = now.duration_since(self.started).as_secs_f64() / self.duration.as_secs_f64();
+        self.from * (1.0 - progress).max(0.0)
+    }
+}
+
+/// Vibration easing in at the start of a game, for `--soft-start-seconds`.
+struct SoftStart {
+    duration: Duration,
+    /// When vibration first started in this game, once it has.
+    started: Option<Instant>,
+}
+
+impl SoftStart {
+    fn new(seconds: f64) -> Self {
+        Self {
+            duration: Duration::from_secs_f64(seconds),
+            started: None,
+        }
+    }
+
+    /// Has vibration started easing in, but not finished yet?
+    fn is_running(&self) -> bool {
+        self.is_running_at(Instant::now())
+    }
+
+    /// [`SoftStart::is_running`] at `now`.
+    fn is_running_at(&self, now: Instant) -> bool {
+        self.started
+            .is_some_and(|started| now.duration_since(started) < self.duration)
+    }
+
+    /// Scale a level by how far into the soft start we are.
+    /// The soft start begins with the first level it scales, so `--arm-delay` doesn't use it up.
+    fn scale(&mut self, level: f64) -> f64 {
+        self.scale_at(level, Instant::now())
+    }
+
+    /// [`SoftStart::scale`] at `now`.
+    fn scale_at(&mut self, level: f64, now: Instant) -> f64 {
+        let started = *self.started.get_or_insert(now);
+        let progress = now.duration_since(started).as_secs_f64() / self.duration.as_secs_f64();
+        level * progress.min(1.0)
+    }
+}
+
+/// Apply the soft start to a level, if there is one, and drop it once it's over.
+fn soft_start_level(soft_start: &mut Option<SoftStart>, level: f64) -> f64 {
+    let Some(current) = soft_start else {
+        return level;
+    };
+    let level = current.scale(level);
+    if !current.is_running() {
+        *soft_start = None;
+        info!("Soft start finished.");
+    }
+    level
+}
+
+/// Lets the vibration level change gradually when APM changes, instead of all at once.
+struct Ramp {
+    /// Fastest the level can rise, in levels per second. Rises are immediate if this is `None`.
+    rise_per_sec: Option<f64>,
+    /// Fastest the level can fall, in levels per second. Falls are immediate if this is `None`.
+    fall_per_sec: Option<f64>,
+    level: f64,
+    updated: Instant,
+}
+
+impl Ramp {
+    /// Build a ramp from `--ramp-rate` and `--engagement-decay-ms`, if either is set.
+    fn from_args(args: &Args) -> Option<Self> {
+        let decay_per_sec = args
+            .engagement_decay_ms
+            .map(|ms| 1.0 / Duration::from_millis(ms).as_secs_f64());
+        let fall_per_sec = match (args.ramp_rate, decay_per_sec) {
+            (Some(ramp_rate), Some(decay_per_sec)) => Some(ramp_rate.min(decay_per_sec)),
+            (ramp_rate, decay_per_sec) => ramp_rate.or(decay_per_sec),
+        };
+        // Falls are limited whenever either option is set.
+        fall_per_sec?;
+        Some(Self {
+            rise_per_sec: args.ramp_rate,
+            fall_per_sec,
+            level: 0.0,
+            updated: Instant::now(),
+        })
+    }
+
+    /// Are we still on the way to the target level?
+    fn is_moving(&self, target: f64) -> bool {
+        (target > self.level && self.rise_per_sec.is_some())
+            || (target < self.level && self.fall_per_sec.is_some())
+    }
+
+    /// Move towards the target level and return the new level.
+    fn update(&mut self, target: f64) -> f64 {
+        self.update_at(target, Instant::now())
+    }
+
+    /// [`Ramp::update`] at `now`.
+    fn update_at(&mut self, target: f64, now: Instant) -> f64 {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.updated = now;
+        self.level = if target > self.level {
+            match self.rise_per_sec {
+                Some(rate) => target.min(self.level + rate * elapsed),
+                None => target,
+            }
+        } else {
+            match self.fall_per_sec {
+                Some(rate) => target.max(self.level - rate * elapsed),
+                None => target,
+            }
+        };
+        self.level
+    }
+
+    /// Vibrators have been stopped, so start again from zero.
+    fn reset(&mut self) {
+        self.level = 0.0;
+        self.updated = Instant::now();
+    }
+}
+
+/// Warn if the APM source has sent nothing but zero APM for this long.
+/// StarCraft only works out APM during a game, so a long run of zeros means it's probably not the APM we're reading.
+const ZERO_APM_WARNING_WAIT: Duration = Duration::from_secs(120);
+
+/// What [`ZeroApmWatch`] noticed.
+#[derive(Debug, PartialEq)]
+enum ZeroApmNotice {
+    /// APM has been zero for [`ZERO_APM_WARNING_WAIT`].
+    LongRun,
+    /// APM isn't zero anymore, after a [`ZeroApmNotice::LongRun`].
+    Recovered,
+}
+
+/// Watches for a long run of nothing but zero APM.
+#[derive(Default)]
+struct ZeroApmWatch {
+    /// When the current run of zeros started, if there is one.
+    since: Option<Instant>,
+    warned: bool,
+}
+
+impl ZeroApmWatch {
+    /// Take an APM received at `now`. Notices a run of zeros once, when it's lasted long enough,
+    /// and then notices when it ends.
+    fn observe_at(&mut self, apm: i32, now: Instant) -> Option<ZeroApmNotice> {
+        if apm != 0 {
+            self.since = None;
+            return std::mem::take(&mut self.warned).then_some(ZeroApmNotice::Recovered);
+        }
+        let since = *self.since.get_or_insert(now);
+        if self.warned || now.duration_since(since) < ZERO_APM_WARNING_WAIT {
+            return None;
+        }
+        self.warned = true;
+        Some(ZeroApmNotice::LongRun)
+    }
+}
+
+/// Warn that the APM source has sent nothing but zeros for [`ZERO_APM_WARNING_WAIT`].
+fn warn_about_zero_apm(args: &Args) {
+    let wait = ZERO_APM_WARNING_WAIT;
+    if args.replay.is_some() {
+        warn!("The replay has had nothing but zero APM for {wait:?}.");
+        return;
+    }
+    match &args.apm_source {
+        ApmSource::Lldb => warn!(
+            "StarCraft has been working out APM for {wait:?}, but lldb reads zero every time. \
+            If you're in a game with the in-game APM display turned on, \
+            StarPlug's lldb script is probably reading APM from the wrong place for this version of StarCraft, \
+            and needs updating for it."
+        ),
+        ApmSource::Pipe(path) => warn!(
+            "{path} has sent nothing but zero APM for {wait:?}. \
+            If you're in a game, check that whatever writes to it is reading your APM.",
+            path = path.display()
+        ),
+        ApmSource::Tcp(addr) => warn!(
+            "The APM source on {addr} has sent nothing but zero APM for {wait:?}. \
+            If you're in a game, check that it's reading your APM."
+        ),
+        // Zero keyboard APM just means nobody's typing.
+        ApmSource::Keyboard => {}
+    }
+}
+
+/// Number of samples `--log-csv-every-sample` can fall behind by before it starts dropping them.
+const SAMPLE_BUFFER: usize = 1024;
+
+/// Window for the rolling average used by `--min-session-apm`.
+const SESSION_APM_WINDOW: Duration = Duration::from_secs(60);
+
+/// Rolling average of APM samples over a fixed time window.
+/// Used by `--min-session-apm` and `--apm-window-seconds`.
+struct RollingApm {
+    window: Duration,
+    started: Instant,
+    samples: VecDeque<(Instant, i32)>,
+}
+
+impl RollingApm {
+    fn new(window: Duration) -> Self {
+        Self {
+            window,
+            started: Instant::now(),
+            samples: VecDeque::new(),
+        }
+    }
+
+    /// Add a sample and return the average of the samples within the window,
+    /// or `None` if we haven't been watching for a full window yet.
+    fn push(&mut self, apm: i32) -> Option<f64> {
+        self.push_at(apm, Instant::now())
+    }
+
+    /// [`RollingApm::push`] for a sample taken at `now`.
+    fn push_at(&mut self, apm: i32, now: Instant) -> Option<f64> {
+        self.samples.push_back((now, apm));
+        while let Some((sampled_at, _)) = self.samples.front() {
+            if now.duration_since(*sampled_at) <= self.window {
+                break;
+            }
+            self.samples.pop_front();
+        }
+        if now.duration_since(self.started) < self.window {
+            return None;
+        }
+        let sum: i64 = self.samples.iter().map(|(_, apm)| *apm as i64).sum();
+        Some(sum as f64 / self.samples.len() as f64)
+    }
+}
+
+/// Python script that we ask `lldb` to run.
+/// Writes status lines like `APM: 69`, after an `ATTACHED: <pid>` line once it's attached
+/// and a `VERSION: <version>` line if it can tell which version of StarCraft it's attached to.
+const STARPLUG_PY: &[u8] = include_bytes!("starplug.py");
+
+/// Launch `lldb` with our instrumentation script and start tracking APM.
+/// May need to wait for StarCraft to be started.
+/// Returns `None` if lldb couldn't attach or never sent any APM, in which case it's worth trying again.
+async fn connect_to_starcraft(
+    args: &Args,
+    running_lldb: Arc<Mutex<Option<ChildShutdown>>>,
+    samples: Option<mpsc::Sender<i32>>,
+) -> Result<Option<watch::Receiver<Status>>> {
+    // Write our internal copy of the lldb script, or the one from `--script`, to a temp file.
+    let custom_script =
+        match &args.script {
+            Some(path) => Some(std::fs::read(path).with_context(|| {
+                format!("Couldn't read lldb script {path}", path = path.display())
+            })?),
+            None => None,
+        };
+    let mut starplug_py = tempfile::Builder::new()
+        .prefix("starplug_")
+        .suffix(".py")
+        .tempfile()?;
+    starplug_py.write_all(custom_script.as_deref().unwrap_or(STARPLUG_PY))?;
+    let starplug_py_path = starplug_py.path();
+
+    // Build an lldb command to run the script.
+    let mut script_arg = OsString::new();
+    script_arg.push("command script import '");
+    script_arg.push(starplug_py_path.as_os_str());
+    script_arg.push("'");
+
+    // Start lldb with that command.
+    // lldb dumps a lot of symbol-related errors when loading the StarCraft binary,
+    // but none of them matter, so we only look for the ones that keep it from attaching.
+    let mut lldb_cmd = Command::new("lldb");
+
+    lldb_cmd
+        .args(["--batch", "--source-quietly", "--one-line"])
+        .arg(script_arg)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+
+    if let Some(pid) = find_starcraft_pid(args) {
+        info!("StarCraft is already running: PID {pid}");
+        lldb_cmd.env("STARCRAFT_PID", pid.to_string());
+    } else if let (None, None, [process_name]) =
+        (args.pid, &args.match_cmdline, args.process_name.as_slice())
+    {
+        info!("StarCraft is not running yet.");
+        // lldb can wait for a process with a single name by itself.
+        lldb_cmd.env("STARCRAFT_PROCESS_NAME", process_name);
+    } else {
+        info!("StarCraft is not running yet. Waiting for it to start…");
+        let pid = loop {
+            sleep(PROCESS_POLL_INTERVAL).await;
+            if let Some(pid) = find_starcraft_pid(args) {
+                break pid;
+            }
+        };
+        info!("StarCraft started: PID {pid}");
+        // Give StarCraft a chance to unpack itself before the script looks at its code.
+        sleep(STARCRAFT_UNPACK_WAIT).await;
+        lldb_cmd.env("STARCRAFT_PID", pid.to_string());
+    }
+
+    let mut lldb = lldb_cmd.spawn()?;
+
+    let lldb_stderr = lldb
+        .stderr
+        .take()
+        .ok_or(anyhow!("Couldn't get lldb's stderr!"))?;
+    let mut lldb_errors = BufReader::new(lldb_stderr).lines();
+    let show_lldb_errors = args.show_lldb_errors;
+    // Forwarded to the log, so it ends up in the log file and doesn't draw over the dashboard.
+    let log_lldb_errors = args.log_file.is_some() || args.tui;
+    // Finishes when lldb does, with whether lldb was denied permission to attach.
+    let attach_denied = tokio::spawn(async move {
+        let mut denied = false;
+        while let Ok(Some(line)) = lldb_errors.next_line().await {
+            denied |= is_attach_permission_error(&line);
+            if !show_lldb_errors {
+                continue;
+            }
+            if log_lldb_errors {
+                warn!(target: "lldb", "{line}");
+            } else {
+                eprintln!("{line}");
+            }
+        }
+        denied
+    });
+
+    let lldb_stdout = lldb
+        .stdout
+        .take()
+        .ok_or(anyhow!("Couldn't get lldb's stdout!"))?;
+    let mut lldb_reader = BufReader::new(lldb_stdout).lines();
+
+    let (apm_tx, apm_rx) = watch::channel(Status::Apm(0));
+
+    // Spawn a task to watch for APM info from lldb.
+    let mut parser = StatusParser::new(args, samples);
+    let (attached_tx, attached_rx) = oneshot::channel::<()>();
+    let (first_apm_tx, first_apm_rx) = oneshot::channel::<()>();
+    tokio::spawn(async move {
+        let mut attached_tx = Some(attached_tx);
+        let mut first_apm_tx = Some(first_apm_tx);
+        while let Ok(Some(line)) = lldb_reader.next_line().await {
+            if line.starts_with("ATTACHED:") {
+                if let Some(attached_tx) = attached_tx.take() {
+                    let _ = attached_tx.send(());
+                }
+                continue;
+            }
+            if let Some(starcraft_version) = line.strip_prefix("VERSION:") {
+                info!("StarCraft version: {}", starcraft_version.trim());
+                continue;
+            }
+            if line.starts_with("APM:") {
+                if let Some(first_apm_tx) = first_apm_tx.take() {
+                    let _ = first_apm_tx.send(());
+                }
+            }
+            if let Some(status) = parser.parse(&line) {
+                if let Err(e) = apm_tx.send(status) {
+                    error!("Couldn't send APM through watch channel: {e:?}");
+                    break;
+                }
+            }
+        }
+        info!("lldb reader task finished.");
+    });
+
+    let (finished_tx, finished_rx) = oneshot::channel::<()>();
+    let pid = lldb.id().ok_or(anyhow!("Couldn't get lldb PID!"))? as i32;
+    *running_lldb.lock().await = Some(ChildShutdown { pid, finished_rx });
 
     // Spawn a task to wait for the lldb process so that it can make progress.
-    let _ = tokio::spawn(async move {
+    tokio::spawn(async move {
         // Hold onto the temporary file until lldb finishes.
         let _starplug_py = starplug_py;
 
-        match lldb.wait().await {
-            Ok(status) => {
-                if status.success() {
-                    info!("lldb exited normally.");
-                } else {
-                    if let Some(code) = status.code() {
-                        error!("lldb exited with code {code}!");
-                    } else {
-                        error!("lldb exited due to a signal!");
-                    }
-                }
-            }
-            Err(e) => error!("Couldn't wait for lldb to exit: {e:?}"),
+        match lldb.wait().await {
+            Ok(status) => {
+                if status.success() {
+                    info!("lldb exited normally.");
+                } else {
+                    if let Some(code) = status.code() {
+                        error!("lldb exited with code {code}!");
+                    } else {
+                        error!("lldb exited due to a signal!");
+                    }
+                }
+            }
+            Err(e) => error!("Couldn't wait for lldb to exit: {e:?}"),
+        }
+        let _ = finished_tx.send(());
+    });
+
+    // lldb's output ends without an attach message if it couldn't attach.
+    if attached_rx.await.is_ok() {
+        info!("lldb attached to StarCraft. Waiting for APM…");
+    } else if attach_denied.await.unwrap_or(false) {
+        bail!(
+            "lldb isn't allowed to attach to StarCraft. \
+            Enable developer mode by running `sudo DevToolsSecurity -enable`, \
+            make sure your account is in the `_developer` group \
+            (`sudo dseditgroup -o edit -a \"$USER\" -t user _developer`), and try again. \
+            If it still fails, System Integrity Protection may be blocking lldb; \
+            run with --show-lldb-errors for details."
+        );
+    } else {
+        error!("lldb couldn't attach to StarCraft! Run with --show-lldb-errors for details.");
+        return Ok(None);
+    }
+
+    // If the script wedges after attaching, lldb would otherwise sit there forever without sending anything.
+    match timeout(LLDB_APM_GRACE, first_apm_rx).await {
+        Ok(Ok(())) => Ok(Some(apm_rx)),
+        Ok(Err(_)) => {
+            error!("lldb exited without sending any APM! Run with --show-lldb-errors for details.");
+            Ok(None)
+        }
+        Err(_) => {
+            error!(
+                "lldb produced no APM data within {grace:?} of attaching to StarCraft, \
+                so StarPlug's lldb script may be stuck. Restarting lldb… \
+                (If StarCraft is still in its menus, this is harmless. \
+                Otherwise, run with --show-lldb-errors to see what went wrong.)",
+                grace = LLDB_APM_GRACE
+            );
+            if let Some(lldb) = running_lldb.lock().await.take() {
+                lldb.terminate().await?;
+            }
+            Ok(None)
+        }
+    }
+}
+
+/// How long lldb gets after attaching to StarCraft to send its first APM, before we restart it.
+const LLDB_APM_GRACE: Duration = Duration::from_secs(90);
+
+/// Does this line from lldb's stderr look like the system refused to let lldb attach?
+fn is_attach_permission_error(line: &str) -> bool {
+    let line = line.to_lowercase();
+    [
+        "not allowed to attach",
+        "operation not permitted",
+        "developer mode",
+        "devtoolssecurity",
+        "system integrity protection",
+    ]
+    .iter()
+    .any(|signature| line.contains(signature))
+}
+
+struct ChildShutdown {
+    // Why i32? See https://github.com/nix-rust/nix/issues/656
+    pid: i32,
+    finished_rx: oneshot::Receiver<()>,
+}
+
+impl ChildShutdown {
+    /// Politely terminate a process.
+    async fn terminate(mut self) -> Result<()> {
+        match self.finished_rx.try_recv() {
+            Ok(()) => {
+                // Already finished.
+                return Ok(());
+            }
+            Err(TryRecvError::Closed) => {
+                // lldb task somehow died before it sent a finished message.
+                // Probably can't happen.
+                return Err(anyhow!(TryRecvError::Closed));
+            }
+            Err(TryRecvError::Empty) => {
+                // Hasn't finished yet. Keep going.
+            }
+        }
+
+        kill_process(self.pid)?;
+        self.finished_rx.await.map_err(|e| anyhow!(e))
+    }
+}
+
+/// Kill a process immediately.
+fn kill_process(pid: i32) -> Result<()> {
+    nix::sys::signal::kill(nix::unistd::Pid::from_raw(pid), nix::sys::signal::SIGKILL)
+        .map_err(|e| anyhow!(e))
+}
+
+/// How often to check whether StarCraft has started, when lldb can't wait for it by itself.
+const PROCESS_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long to let StarCraft run after it starts before looking at its code.
+const STARCRAFT_UNPACK_WAIT: Duration = Duration::from_secs(10);
+
+/// Get the PID of the running StarCraft process, if there is one.
+/// If there's more than one, pick the most recently started, since an older one is probably orphaned.
+fn find_starcraft_pid(args: &Args) -> Option<Pid> {
+    let mut candidates = find_starcraft_candidates(args);
+    candidates.sort_by_key(|(_, start_time)| *start_time);
+    let (pid, _) = *candidates.last()?;
+    if candidates.len() > 1 {
+        info!(
+            "Found {count} StarCraft processes: PIDs {pids}. \
+            Using PID {pid} because it started most recently. Use --pid to choose a different one.",
+            count = candidates.len(),
+            pids = candidates
+                .iter()
+                .map(|(pid, _)| pid.to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+        );
+    }
+    Some(pid)
+}
+
+/// Is StarCraft still running?
+/// Unlike `find_starcraft_pid`, this never logs, so it's fine to call over and over.
+fn starcraft_is_running(args: &Args) -> bool {
+    !find_starcraft_candidates(args).is_empty()
+}
+
+/// Get the PIDs and start times of every process that could be StarCraft.
+fn find_starcraft_candidates(args: &Args) -> Vec<(Pid, u64)> {
+    if let Some(pid) = args.pid {
+        let pid = Pid::from_u32(pid);
+        return find_pids(|process| process.pid() == pid);
+    }
+    match &args.match_cmdline {
+        Some(substring) => {
+            find_pids(|process| process.cmd().join(" ").contains(substring.as_str()))
+        }
+        None => find_pids(|process| {
+            process.exe().file_name().is_some_and(|file_name| {
+                args.process_name
+                    .iter()
+                    .any(|process_name| exe_name_matches(file_name, process_name))
+            })
+        }),
+    }
+}
+
+/// Get the PID of the first running process with this executable name, if there is one.
+fn find_pid_by_exe_name(name: &str) -> Option<Pid> {
+    find_pids(|process| {
+        process
+            .exe()
+            .file_name()
+            .is_some_and(|file_name| exe_name_matches(file_name, name))
+    })
+    .first()
+    .map(|(pid, _)| *pid)
+}
+
+/// Does an executable's file name match a process name, ignoring any `.exe` on either,
+/// so `StarCraft` and `StarCraft.exe` match each other but not `StarCraft Launcher.exe`?
+fn exe_name_matches(file_name: &OsStr, name: &str) -> bool {
+    fn stem(name: &str) -> &str {
+        match name.len().checked_sub(4) {
+            Some(split)
+                if name.is_char_boundary(split) && name[split..].eq_ignore_ascii_case(".exe") =>
+            {
+                &name[..split]
+            }
+            _ => name,
+        }
+    }
+    file_name
+        .to_str()
+        .is_some_and(|file_name| stem(file_name) == stem(name))
+}
+
+/// Get the PIDs and start times of all running processes that match.
+fn find_pids(matches: impl Fn(&sysinfo::Process) -> bool) -> Vec<(Pid, u64)> {
+    let system =
+        System::new_with_specifics(RefreshKind::new().with_processes(ProcessRefreshKind::new()));
+    system
+        .processes()
+        .iter()
+        .filter(|(_, process)| matches(process))
+        .map(|(pid, process)| (*pid, process.start_time()))
+        .collect()
+}
+
+/// Stop every device on every server.
+/// This stops all of their actuators, so constrictors are released too.
+async fn stop_all_vibrators(clients: &[Arc<Mutex<ButtplugClient>>], output: &Output) {
+    output.set(0.0);
+    output.set_apm(None);
+    output.stop_workers();
+    let mut connected = vec![];
+    for client in clients {
+        let client = client.lock().await;
+        // If we're not connected, there's nothing to stop, and the stop command would just fail.
+        if client.connected() {
+            connected.push(client);
+        }
+    }
+    if connected.is_empty() {
+        return;
+    }
+    info!("Stopping all vibrators…");
+    let stops = connected.iter().map(|client| client.stop_all_devices());
+    for result in join_all(stops).await {
+        if let Err(e) = result {
+            error!("Error stopping all vibrators: {e:?}");
+        }
+    }
+    info!("Stopped all vibrators.");
+}
+
+/// How long to wait for each device to acknowledge a stop command when quitting.
+const STOP_CONFIRM_WAIT: Duration = Duration::from_secs(2);
+
+/// Stop every device on every server one by one and wait until each acknowledges or times out.
+/// Returns the locked clients so the caller can hold them, which keeps anything else from
+/// starting a device again afterward.
+async fn stop_all_vibrators_and_confirm(
+    clients: &[Arc<Mutex<ButtplugClient>>],
+) -> Vec<tokio::sync::MutexGuard<'_, ButtplugClient>> {
+    let mut locked = vec![];
+    for client in clients {
+        locked.push(client.lock().await);
+    }
+    let devices: Vec<Arc<ButtplugClientDevice>> = locked
+        .iter()
+        .filter(|client| client.connected())
+        .flat_map(|client| client.devices())
+        .collect();
+    if devices.is_empty() {
+        return locked;
+    }
+
+    info!("Stopping all devices and waiting for confirmation…");
+    let confirmations = devices.iter().map(|device| async move {
+        let name = device.name();
+        match timeout(STOP_CONFIRM_WAIT, device.stop()).await {
+            Ok(Ok(())) => {
+                info!("{name} stopped.");
+                true
+            }
+            Ok(Err(e)) => {
+                error!("Error stopping {name}: {e:?}");
+                false
+            }
+            Err(_) => {
+                error!("{name} didn't confirm that it stopped!");
+                false
+            }
+        }
+    });
+    if join_all(confirmations)
+        .await
+        .into_iter()
+        .all(|stopped| stopped)
+    {
+        info!("All devices stopped.");
+    } else {
+        warn!("Some devices may still be running!");
+    }
+    locked
+}
+
+async fn apm_changed(
+    args: &Args,
+    apm: i32,
+    level: f64,
+    clients: &[Arc<Mutex<ButtplugClient>>],
+    output: &Output,
+    events: &Events,
+) {
+    let rate = args.display_units.format(apm as f64);
+    debug!("{rate} mapped to vibration level {level}");
+    if output.is_emergency_stopped() {
+        events.emit(Event::Apm { apm, level: 0.0 });
+        return;
+    }
+    output.set_apm(Some(apm));
+    events.emit(Event::Apm { apm, level });
+    let percent = (level * 100.0).round();
+    if args.dry_run {
+        output.set(level);
+        info!("{rate} → {percent}%");
+        return;
+    }
+    let device_count = vibrate_all(args, clients, output, level).await;
+    let devices = if device_count == 1 {
+        "device"
+    } else {
+        "devices"
+    };
+    info!("{rate} → {percent}% to {device_count} {devices}");
+}
+
+/// One client per Intiface server, in `--server` order.
+type Clients = Arc<[Arc<Mutex<ButtplugClient>>]>;
+
+/// What we want devices to be doing, shared by everything that sends them commands.
+#[derive(Clone)]
+struct Output {
+    /// The most recent level, kept so it can be resent after reconnecting to Intiface or resuming,
+    /// and watched by the stroker task.
+    level: Arc<watch::Sender<f64>>,
+    /// While paused, levels are still tracked but not sent.
+    paused: Arc<AtomicBool>,
+    /// After an emergency stop, levels are neither tracked nor sent until it's released.
+    stopped: Arc<AtomicBool>,
+    /// The most recent APM, for devices with a `--device-band`. None after stopping.
+    apm: Arc<std::sync::Mutex<Option<i32>>>,
+    /// One for each device we've sent a level to.
+    workers: Arc<std::sync::Mutex<Vec<DeviceWorker>>>,
+    /// Names of devices that kept failing, left out until they reconnect.
+    /// Stopping doesn't clear this, since stopping drops every worker.
+    disabled: Arc<std::sync::Mutex<BTreeSet<String>>>,
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        Self {
+            level: Arc::new(watch::channel(0.0).0),
+            paused: Arc::new(AtomicBool::new(false)),
+            stopped: Arc::new(AtomicBool::new(false)),
+            apm: Arc::new(std::sync::Mutex::new(None)),
+            workers: Arc::new(std::sync::Mutex::new(vec![])),
+            disabled: Arc::new(std::sync::Mutex::new(BTreeSet::new())),
+        }
+    }
+}
+
+impl Output {
+    fn get(&self) -> f64 {
+        *self.level.borrow()
+    }
+
+    fn set(&self, level: f64) {
+        self.level.send_replace(level);
+    }
+
+    fn apm(&self) -> Option<i32> {
+        *self.apm.lock().unwrap()
+    }
+
+    fn set_apm(&self, apm: Option<i32>) {
+        *self.apm.lock().unwrap() = apm;
+    }
+
+    fn subscribe(&self) -> watch::Receiver<f64> {
+        self.level.subscribe()
+    }
+
+    fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Drop any levels that haven't been sent yet, so they can't start a device after it's stopped.
+    fn stop_workers(&self) {
+        for worker in self.workers.lock().unwrap().drain(..) {
+            worker.target.send_replace(None);
         }
-        let _ = finished_tx.send(());
-    });
+    }
+
+    /// Stop sending levels to a device until it reconnects.
+    fn disable_device(&self, name: &str) {
+        self.disabled.lock().unwrap().insert(name.to_owned());
+    }
+
+    /// A device reconnected, so try it again.
+    fn enable_device(&self, name: &str) {
+        self.disabled.lock().unwrap().remove(name);
+    }
+
+    fn is_device_disabled(&self, name: &str) -> bool {
+        self.disabled.lock().unwrap().contains(name)
+    }
+
+    /// Pause if we're running or resume if we're paused, and return whether we're now paused.
+    fn toggle_paused(&self) -> bool {
+        !self.paused.fetch_xor(true, Ordering::SeqCst)
+    }
 
-    Ok(apm_rx)
+    fn is_emergency_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
 }
 
-struct ChildShutdown {
-    // Why i32? See https://github.com/nix-rust/nix/issues/656
-    pid: i32,
-    finished_rx: oneshot::Receiver<()>,
+/// Stop every device right away, skipping any ramp or fade, and ignore APM until [`release_emergency_stop`].
+async fn emergency_stop(clients: Clients, output: Output) {
+    if output.stopped.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    stop_all_vibrators(&clients, &output).await;
+    warn!("EMERGENCY STOP: all devices stopped, and APM is being ignored. Type `unlock` and press Enter (or press U in the dashboard) to allow vibration again.");
 }
 
-impl ChildShutdown {
-    /// Politely terminate a process.
-    async fn terminate(mut self) -> Result<()> {
-        match self.finished_rx.try_recv() {
-            Ok(()) => {
-                // Already finished.
-                return Ok(());
+/// Allow vibration again after [`emergency_stop`]. Devices start again with the next APM change.
+fn release_emergency_stop(output: &Output) {
+    if output.stopped.swap(false, Ordering::SeqCst) {
+        info!("Emergency stop released. Vibration will pick up with the next APM change.");
+    }
+}
+
+/// Pause or resume vibration each time Enter is pressed.
+/// `s` and Enter is an emergency stop instead, and `unlock` and Enter releases it.
+/// Does nothing if there's no terminal to read from.
+async fn pause_on_enter(args: Arc<Args>, clients: Clients, output: Output) {
+    // Reading stdin blocks, and can't be cancelled, so it gets its own thread:
+    // on tokio's blocking pool it would keep StarPlug from exiting after Ctrl-C.
+    let (enter_tx, mut enter_rx) = tokio::sync::mpsc::unbounded_channel::<String>();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            if enter_tx.send(line).is_err() {
+                break;
             }
-            Err(TryRecvError::Closed) => {
-                // lldb task somehow died before it sent a finished message.
-                // Probably can't happen.
-                return Err(anyhow!(TryRecvError::Closed));
+        }
+    });
+    while let Some(line) = enter_rx.recv().await {
+        match line.trim() {
+            "s" => {
+                emergency_stop(clients.clone(), output.clone()).await;
+                continue;
             }
-            Err(TryRecvError::Empty) => {
-                // Hasn't finished yet. Keep going.
+            "unlock" => {
+                release_emergency_stop(&output);
+                continue;
             }
+            _ => {}
         }
+        toggle_pause(&args, &clients, &output, "Press Enter to resume vibration.").await;
+    }
+}
 
-        nix::sys::signal::kill(
-            nix::unistd::Pid::from_raw(self.pid),
-            nix::sys::signal::SIGKILL,
-        )
-        .map_err(|e| anyhow!(e))?;
-        self.finished_rx.await.map_err(|e| anyhow!(e))
+/// Pause if we're running, stopping every device, or resume if we're paused.
+/// `resume_hint` says how to resume.
+async fn toggle_pause(args: &Args, clients: &Clients, output: &Output, resume_hint: &str) {
+    if output.toggle_paused() {
+        info!("Paused. {resume_hint}");
+        // Drop levels that workers haven't sent yet, or they'd start devices again after the stop.
+        output.stop_workers();
+        for client in clients.iter() {
+            let client = client.lock().await;
+            if !client.connected() {
+                continue;
+            }
+            if let Err(e) = client.stop_all_devices().await {
+                error!("Error stopping all vibrators: {e:?}");
+            }
+        }
+    } else {
+        info!("Resumed.");
+        vibrate_all(args, clients, output, output.get()).await;
     }
 }
 
-/// Get the PID of the first running StarCraft process, if there is one.
-fn find_starcraft_pid() -> Option<Pid> {
-    let system =
-        System::new_with_specifics(RefreshKind::new().with_processes(ProcessRefreshKind::new()));
-    system.processes().iter().find_map(|(pid, process)| {
-        if process.exe().file_name() == Some(&OsString::from("StarCraft")) {
-            Some(*pid)
+/// Time for one stroke at the lowest level.
+const SLOWEST_STROKE: Duration = Duration::from_millis(1000);
+
+/// Time for one stroke at full level.
+const FASTEST_STROKE: Duration = Duration::from_millis(200);
+
+/// Move strokers back and forth between the stroke endpoints, faster at higher levels.
+/// Each stroke finishes before the next one picks up a new level, except that strokes stop as soon as
+/// the level drops to zero, which is what stopping all vibrators does.
+async fn stroke_all(args: Arc<Args>, clients: Clients, output: Output) {
+    let mut level_rx = output.subscribe();
+    let mapper = ApmMapper::new(&args);
+    let failures = Arc::new(std::sync::Mutex::new(FailureCounts::default()));
+    let mut outward = true;
+    loop {
+        let level = *level_rx.borrow_and_update();
+        if level <= 0.0 {
+            if level_rx.changed().await.is_err() {
+                return;
+            }
+            continue;
+        }
+
+        let level = mapper.cap(level);
+        let duration = SLOWEST_STROKE.mul_f64(1.0 - level) + FASTEST_STROKE.mul_f64(level);
+        let position = if outward {
+            args.stroke_max_pos
         } else {
-            None
+            args.stroke_min_pos
+        };
+        outward = !outward;
+        if !output.is_paused() {
+            send_stroke(&args, &clients, &output, &failures, duration, position).await;
         }
-    })
+
+        let stroke_done = sleep(duration).fuse();
+        futures::pin_mut!(stroke_done);
+        loop {
+            select! {
+                _ = stroke_done => break,
+                changed = level_rx.changed().fuse() => {
+                    if changed.is_err() {
+                        return;
+                    }
+                    if *level_rx.borrow() <= 0.0 {
+                        break;
+                    }
+                }
+            }
+        }
+    }
 }
 
-async fn stop_all_vibrators(client: Arc<Mutex<ButtplugClient>>) {
-    info!("Stopping all vibrators…");
-    if let Err(e) = client.lock().await.stop_all_devices().await {
-        error!("Error stopping all vibrators: {e:?}");
+/// Warn once about each `--device-scale` or `--device-band` that doesn't name any device we're driving.
+fn warn_about_unmatched_device_names(args: &Args, devices: &[Arc<ButtplugClientDevice>]) {
+    static WARNED: std::sync::Mutex<BTreeSet<(&str, String)>> =
+        std::sync::Mutex::new(BTreeSet::new());
+
+    if devices.is_empty() {
+        return;
+    }
+    let mut warned = WARNED.lock().unwrap();
+    let named = args
+        .device_scale
+        .iter()
+        .map(|device_scale| ("--device-scale", &device_scale.name))
+        .chain(
+            args.device_band
+                .iter()
+                .map(|band| ("--device-band", &band.name)),
+        );
+    for (flag, name) in named {
+        let key = (flag, name.clone());
+        if warned.contains(&key) || devices.iter().any(|device| device.name() == name) {
+            continue;
+        }
+        warn!(
+            "No device named {name:?} is connected, so its {flag} isn't being used yet. Devices in use: {names}",
+            names = devices
+                .iter()
+                .map(|device| device.name().as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        warned.insert(key);
     }
-    info!("Stopped all vibrators.");
 }
 
-async fn apm_changed(args: &Args, apm: i32, client: Arc<Mutex<ButtplugClient>>) {
-    let apm_range = (args.max_apm - args.min_apm) as f64;
-    let level = ((apm - args.min_apm) as f64 / apm_range).clamp(0f64, 1f64);
-    info!("APM {apm} mapped to vibration level {level}");
+/// Send one stroke to every connected stroker that we're allowed to use.
+async fn send_stroke(
+    args: &Args,
+    clients: &[Arc<Mutex<ButtplugClient>>],
+    output: &Output,
+    failures: &Arc<std::sync::Mutex<FailureCounts>>,
+    duration: Duration,
+    position: f64,
+) {
+    for device in connected_devices(clients)
+        .await
+        .into_iter()
+        .filter(|device| {
+            device.is_stroker()
+                && args.allows_device(device.name())
+                && !output.is_device_disabled(device.name())
+                && args
+                    .device_actuators(device.name())
+                    .contains(&Actuator::Linear)
+        })
+    {
+        let command = LinearCommand::Linear(duration.as_millis() as u32, position);
+        let output = output.clone();
+        let failures = failures.clone();
+        spawn(async move {
+            let result = device.linear(&command).await;
+            if let Err(e) = &result {
+                error!(
+                    "Error sending stroke command to {name}: {e:?}",
+                    name = device.name()
+                );
+            }
+            if failures
+                .lock()
+                .unwrap()
+                .record(device.name(), result.is_err())
+            {
+                // Like `drive_device`, give up until the device reconnects.
+                output.disable_device(device.name());
+                warn!(
+                    "{name} failed {DEVICE_FAILURE_LIMIT} times in a row. Not sending it anything else until it reconnects.",
+                    name = device.name()
+                );
+            }
+        });
+    }
+}
 
-    let client = client.lock().await;
+/// How long to let Intiface look for devices before the self-test.
+const SELF_TEST_SCAN_WAIT: Duration = Duration::from_secs(3);
 
-    if !client.connected() {
+/// Level to run each actuator at during the self-test, before `--max-intensity`.
+const SELF_TEST_LEVEL: f64 = 0.2;
+
+/// How long to run each actuator for during the self-test.
+const SELF_TEST_PULSE: Duration = Duration::from_secs(1);
+
+/// Run each actuator on each device we drive briefly, one at a time.
+/// Devices that were given up on are left out.
+async fn self_test(args: &Args, clients: &[Arc<Mutex<ButtplugClient>>], output: &Output) {
+    info!("Self-test: looking for devices…");
+    sleep(SELF_TEST_SCAN_WAIT).await;
+    let devices: Vec<Arc<ButtplugClientDevice>> = connected_devices(clients)
+        .await
+        .into_iter()
+        .filter(|device| args.drives(device) && !output.is_device_disabled(device.name()))
+        .collect();
+    if devices.is_empty() {
+        warn!("Self-test: no devices found. Make sure they're on and paired with Intiface.");
         return;
     }
 
-    for vibrator in client
-        .devices()
-        .iter()
-        .filter(|device| device.is_vibrator())
-    {
-        let vibrator = vibrator.clone();
-        // Send vibration commands in parallel.
-        let _ = spawn(async move {
-            if let Err(e) = vibrator.vibrate(&VibrateCommand::Speed(level)).await {
+    let level = ApmMapper::new(args).cap(SELF_TEST_LEVEL);
+    for device in devices {
+        let name = device.name();
+        let mut actuator_types: Vec<ActuatorType> = vec![];
+        for scalar_cmd in device.message_attributes().scalar_cmd().iter().flatten() {
+            if !actuator_types.contains(scalar_cmd.actuator_type()) {
+                actuator_types.push(*scalar_cmd.actuator_type());
+            }
+        }
+        for actuator_type in actuator_types {
+            info!("Self-test: running {name}'s {actuator_type} actuators…");
+            let command = device.scalar_cmd_for(actuator_type, level, &[]);
+            self_test_pulse(&device, device.scalar(&command)).await;
+        }
+        if device.is_rotator() {
+            info!("Self-test: running {name}'s rotation motors…");
+            self_test_pulse(&device, device.rotate(&RotateCommand::Rotate(level, true))).await;
+        }
+        if device.is_stroker() {
+            info!("Self-test: moving {name}'s stroker…");
+            let command = LinearCommand::Linear(
+                SELF_TEST_PULSE.as_millis() as u32,
+                args.stroke_min_pos + level * (args.stroke_max_pos - args.stroke_min_pos),
+            );
+            self_test_pulse(&device, device.linear(&command)).await;
+        }
+    }
+    info!("Self-test finished.");
+}
+
+/// Send one self-test command, then stop the device after a moment.
+async fn self_test_pulse(
+    device: &ButtplugClientDevice,
+    command: impl std::future::Future<Output = Result<(), ButtplugClientError>>,
+) {
+    let name = device.name();
+    if let Err(e) = command.await {
+        error!("Self-test: {name} didn't accept the command: {e:?}");
+        return;
+    }
+    sleep(SELF_TEST_PULSE).await;
+    if let Err(e) = device.stop().await {
+        error!("Self-test: couldn't stop {name}: {e:?}");
+    }
+}
+
+/// Every device on every server that we're connected to.
+async fn connected_devices(
+    clients: &[Arc<Mutex<ButtplugClient>>],
+) -> Vec<Arc<ButtplugClientDevice>> {
+    let mut devices = vec![];
+    for client in clients {
+        let client = client.lock().await;
+        if client.connected() {
+            devices.extend(client.devices());
+        }
+    }
+    devices
+}
+
+/// How often to read the battery level of connected devices.
+const BATTERY_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Log battery levels of devices that report them, and warn when one gets low.
+async fn watch_batteries(args: Arc<Args>, clients: Clients, events: Events) {
+    let mut last_levels: HashMap<String, f64> = HashMap::new();
+    // Devices whose battery we couldn't read. We don't ask them again.
+    let mut unsupported: HashSet<String> = HashSet::new();
+    loop {
+        for device in connected_devices(&clients).await {
+            let name = device.name().clone();
+            if unsupported.contains(&name) {
+                continue;
+            }
+            let level = match device.battery_level().await {
+                Ok(level) => level,
+                Err(e) => {
+                    debug!("Couldn't read battery level of {name}, so not trying again: {e}");
+                    unsupported.insert(name);
+                    continue;
+                }
+            };
+            let prev_level = last_levels.insert(name.clone(), level);
+            if prev_level != Some(level) {
+                info!("{name} battery: {percent:.0}%", percent = level * 100.0);
+                let was_low = prev_level
+                    .zip(args.battery_warn)
+                    .is_some_and(|(prev, warn)| prev < warn);
+                if args.battery_warn.is_some_and(|warn| level < warn) && !was_low {
+                    warn!(
+                        "{name} battery is low: {percent:.0}%",
+                        percent = level * 100.0
+                    );
+                }
+            }
+            events.emit(Event::Battery { name, level });
+        }
+        sleep(BATTERY_POLL_INTERVAL).await;
+    }
+}
+
+/// Map a level into `--constrict-min` to `--constrict-max`.
+/// A level of 0 still releases completely.
+fn constrict_level(args: &Args, level: f64) -> f64 {
+    if level <= 0.0 {
+        return 0.0;
+    }
+    args.constrict_min + level * (args.constrict_max - args.constrict_min)
+}
+
+/// Levels for each of a device's actuator types, while the shared level is `level`.
+fn device_target(
+    args: &Args,
+    mapper: &ApmMapper,
+    name: &str,
+    devices: usize,
+    level: f64,
+    apm: Option<i32>,
+) -> DeviceTarget {
+    let level_for = |actuator| args.actuator_level(mapper, actuator, name, devices, level, apm);
+    DeviceTarget {
+        vibrate: level_for(Actuator::Vibrate),
+        rotate: level_for(Actuator::Rotate),
+        oscillate: level_for(Actuator::Oscillate),
+        squeeze: constrict_level(args, level_for(Actuator::Constrict)),
+    }
+}
+
+/// Levels for one device's actuators.
+#[derive(Clone, Copy)]
+struct DeviceTarget {
+    vibrate: f64,
+    rotate: f64,
+    oscillate: f64,
+    squeeze: f64,
+}
+
+/// Task that sends commands to one device, so a slow device only ever has one command in flight,
+/// and gets the latest level next instead of a backlog.
+struct DeviceWorker {
+    device: Arc<ButtplugClientDevice>,
+    /// `None` tells the task to stop.
+    target: watch::Sender<Option<DeviceTarget>>,
+}
+
+/// Default `--min-command-interval` when replaying faster than recorded,
+/// which would otherwise send devices levels faster than they can take them.
+const FAST_REPLAY_COMMAND_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Give up on a device after this many levels in a row that it couldn't be sent.
+const DEVICE_FAILURE_LIMIT: u32 = 5;
+
+/// Failures in a row for each device, for devices that don't have a `drive_device` task to count them.
+#[derive(Default)]
+struct FailureCounts(HashMap<String, u32>);
+
+impl FailureCounts {
+    /// Count a command to this device that failed or didn't.
+    /// Returns whether it's now failed [`DEVICE_FAILURE_LIMIT`] times in a row, so it's time to give up on it.
+    fn record(&mut self, name: &str, failed: bool) -> bool {
+        if !failed {
+            self.0.remove(name);
+            return false;
+        }
+        let failures = self.0.entry(name.to_owned()).or_default();
+        *failures += 1;
+        if *failures < DEVICE_FAILURE_LIMIT {
+            return false;
+        }
+        // Start over if the device reconnects.
+        self.0.remove(name);
+        true
+    }
+}
+
+/// Send each new target to a device until told to stop, or until it keeps failing.
+async fn drive_device(
+    device: Arc<ButtplugClientDevice>,
+    mut target_rx: watch::Receiver<Option<DeviceTarget>>,
+    output: Output,
+    actuators: Vec<Actuator>,
+    actuator_map: Vec<ActuatorMapping>,
+    min_command_interval: Option<Duration>,
+) {
+    let vibrate = actuators.contains(&Actuator::Vibrate);
+    let rotate = actuators.contains(&Actuator::Rotate);
+    let oscillate = actuators.contains(&Actuator::Oscillate);
+    let constrict = actuators.contains(&Actuator::Constrict);
+    // Send each motor one command, not one for each actuator type it's listed under.
+    let shared_oscillators = if vibrate && device.is_vibrator() {
+        device.oscillators_sharing_vibrator_motors()
+    } else {
+        vec![]
+    };
+    if oscillate && !shared_oscillators.is_empty() {
+        info!(
+            "{name}'s oscillate actuators {indexes:?} share motors with its vibrate actuators, so they'll only get vibrate commands.",
+            name = device.name(),
+            indexes = shared_oscillators
+        );
+    }
+    let mut failures = 0;
+    loop {
+        let Some(DeviceTarget {
+            vibrate: vibrate_level,
+            rotate: rotate_level,
+            oscillate: oscillate_level,
+            squeeze,
+        }) = *target_rx.borrow_and_update()
+        else {
+            return;
+        };
+        let mut failed = false;
+        let sent = Instant::now();
+        if vibrate && device.is_vibrator() {
+            let command = device.vibrate_cmd_for(vibrate_level, &actuator_map);
+            if let Err(e) = device.scalar(&command).await {
                 error!(
                     "Error sending vibration command to {name}: {e:?}",
-                    name = vibrator.name()
+                    name = device.name()
                 );
+                failed = true;
             }
-        });
+        }
+        if rotate && device.is_rotator() {
+            if let Err(e) = device
+                .rotate(&RotateCommand::Rotate(rotate_level, true))
+                .await
+            {
+                error!(
+                    "Error sending rotation command to {name}: {e:?}",
+                    name = device.name()
+                );
+                failed = true;
+            }
+        }
+        let command = device.scalar_cmd_for(
+            ActuatorType::Oscillate,
+            oscillate_level,
+            &shared_oscillators,
+        );
+        let has_oscillators =
+            matches!(&command, ScalarCommand::ScalarMap(levels) if !levels.is_empty());
+        if oscillate && has_oscillators {
+            if let Err(e) = device.scalar(&command).await {
+                error!(
+                    "Error sending oscillation command to {name}: {e:?}",
+                    name = device.name()
+                );
+                failed = true;
+            }
+        }
+        if constrict && device.is_constrictor() {
+            let command = device.scalar_cmd_for(ActuatorType::Constrict, squeeze, &[]);
+            if let Err(e) = device.scalar(&command).await {
+                error!(
+                    "Error sending constriction command to {name}: {e:?}",
+                    name = device.name()
+                );
+                failed = true;
+            }
+        }
+        if !failed {
+            failures = 0;
+        } else {
+            failures += 1;
+            if failures == DEVICE_FAILURE_LIMIT {
+                // `vibrate_all` leaves disabled devices out, and they're enabled again when they reconnect.
+                output.disable_device(device.name());
+                warn!(
+                    "{name} failed {failures} times in a row. Not sending it anything else until it reconnects.",
+                    name = device.name()
+                );
+                return;
+            }
+        }
+        if let Some(min_command_interval) = min_command_interval {
+            // Whatever target is newest when this is up gets sent next.
+            sleep_until((sent + min_command_interval).into()).await;
+        }
+        if target_rx.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Send a level to every actuator we drive on every connected device that we're allowed to use.
+/// Returns the number of devices it was sent to.
+async fn vibrate_all(
+    args: &Args,
+    clients: &[Arc<Mutex<ButtplugClient>>],
+    output: &Output,
+    level: f64,
+) -> usize {
+    vibrate_all_with_apm(args, clients, output, level, output.apm()).await
+}
+
+/// [`vibrate_all`], with devices that have a `--device-band` following `apm` instead of the latest APM.
+/// If `apm` is `None`, they get `level` like every other device.
+async fn vibrate_all_with_apm(
+    args: &Args,
+    clients: &[Arc<Mutex<ButtplugClient>>],
+    output: &Output,
+    level: f64,
+    apm: Option<i32>,
+) -> usize {
+    // After an emergency stop, nothing gets to start a device, not even ramps and fades.
+    if output.is_emergency_stopped() {
+        return 0;
+    }
+    // Remember the level even if we can't send it right now, so it can be sent after reconnecting.
+    output.set(level);
+    if output.is_paused() {
+        return 0;
+    }
+
+    // Strokers aren't sent levels: `stroke_all` drives them.
+    let devices: Vec<Arc<ButtplugClientDevice>> = connected_devices(clients)
+        .await
+        .into_iter()
+        .filter(|device| {
+            args.allows_device(device.name())
+                && args
+                    .device_actuators(device.name())
+                    .iter()
+                    .any(|actuator| *actuator != Actuator::Linear && device.has_actuator(*actuator))
+        })
+        .collect();
+
+    warn_about_unmatched_device_names(args, &devices);
+
+    let mapper = ApmMapper::new(args);
+    let mut workers = output.workers.lock().unwrap();
+    // Pausing sets the flag before it takes this lock to drop pending levels,
+    // so checking again here keeps a level from slipping in after a pause.
+    if output.is_paused() {
+        return 0;
+    }
+    // Devices that are gone, or that we don't drive anymore, don't need their workers.
+    workers.retain(|worker| {
+        devices
+            .iter()
+            .any(|device| Arc::ptr_eq(device, &worker.device))
+    });
+    // Leave out devices whose workers gave up on them.
+    let devices: Vec<Arc<ButtplugClientDevice>> = devices
+        .into_iter()
+        .filter(|device| !output.is_device_disabled(device.name()))
+        .collect();
+    let device_count = devices.len();
+    for device in devices {
+        let target = device_target(args, &mapper, device.name(), device_count, level, apm);
+        match workers
+            .iter()
+            .find(|worker| Arc::ptr_eq(&worker.device, &device))
+        {
+            Some(worker) => {
+                worker.target.send_replace(Some(target));
+            }
+            None => {
+                let (target_tx, target_rx) = watch::channel(Some(target));
+                spawn(drive_device(
+                    device.clone(),
+                    target_rx,
+                    output.clone(),
+                    args.device_actuators(device.name()).to_vec(),
+                    args.device_actuator_map(device.name()).to_vec(),
+                    args.device_entry(device.name())
+                        .and_then(|entry| entry.min_command_interval)
+                        .or(args.min_command_interval),
+                ));
+                workers.push(DeviceWorker {
+                    device,
+                    target: target_tx,
+                });
+            }
+        }
+    }
+    device_count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn actuator_types_follow_their_own_thresholds() {
+        let mut args = Args::parse_from(["starplug", "--min-apm", "60", "--max-apm", "120"]);
+        args.actuator_thresholds = vec![(
+            Actuator::Rotate,
+            ActuatorThresholds {
+                min_apm: Some(90),
+                ..ActuatorThresholds::default()
+            },
+        )];
+        let mapper = ApmMapper::new(&args);
+        // At 105 APM, the shared range (60 to 120) gives 0.75, and rotate's (90 to 120) gives 0.5.
+        let target = device_target(&args, &mapper, "Toy", 1, mapper.level(105), Some(105));
+        assert!((target.vibrate - 0.75).abs() < 1e-12);
+        assert!((target.oscillate - 0.75).abs() < 1e-12);
+        assert!((target.rotate - 0.5).abs() < 1e-12);
+
+        // Without an APM yet, every type gets the shared level.
+        let target = device_target(&args, &mapper, "Toy", 1, 0.25, None);
+        assert!((target.rotate - 0.25).abs() < 1e-12);
+    }
+
+    #[test]
+    fn shared_motor_indexes_match_by_feature_descriptor() {
+        let actuators = vec![
+            ("Motor".to_owned(), ActuatorType::Vibrate),
+            ("Motor".to_owned(), ActuatorType::Oscillate),
+            ("Rotator".to_owned(), ActuatorType::Oscillate),
+            ("N/A".to_owned(), ActuatorType::Vibrate),
+            ("N/A".to_owned(), ActuatorType::Oscillate),
+        ];
+        assert_eq!(
+            shared_motor_indexes(&actuators, ActuatorType::Vibrate, ActuatorType::Oscillate),
+            vec![1]
+        );
+        assert_eq!(
+            shared_motor_indexes(&actuators, ActuatorType::Vibrate, ActuatorType::Constrict),
+            Vec::<u32>::new()
+        );
+    }
+
+    #[test]
+    fn exe_name_matches_with_or_without_exe() {
+        let matches = |file_name: &str, name: &str| exe_name_matches(OsStr::new(file_name), name);
+        assert!(matches("StarCraft", "StarCraft"));
+        assert!(matches("StarCraft.exe", "StarCraft"));
+        assert!(matches("StarCraft.EXE", "StarCraft"));
+        assert!(matches("StarCraft", "StarCraft.exe"));
+        assert!(matches("StarCraft.exe", "StarCraft.Exe"));
+    }
+
+    #[test]
+    fn exe_name_matches_only_the_exact_stem() {
+        let matches = |file_name: &str, name: &str| exe_name_matches(OsStr::new(file_name), name);
+        assert!(!matches("StarCraft Launcher.exe", "StarCraft"));
+        assert!(!matches("StarCraft Launcher", "StarCraft"));
+        assert!(!matches("StarCraft", "StarCraft Launcher"));
+        assert!(!matches("starcraft.exe", "StarCraft"));
+        assert!(!matches("StarCraft.exe.bak", "StarCraft"));
+        assert!(!matches(".exe", "StarCraft"));
+    }
+
+    #[test]
+    fn normalize_server_fills_in_the_port() {
+        let normalized = |server: &str| normalize_server(server).unwrap();
+        assert_eq!(normalized("ws://localhost"), "ws://localhost:12345");
+        assert_eq!(normalized("ws://localhost:54817"), "ws://localhost:54817");
+        assert_eq!(
+            normalized(" ws://localhost:54817/ "),
+            "ws://localhost:54817"
+        );
+        assert_eq!(
+            normalized("WSS://intiface.local"),
+            "wss://intiface.local:12345"
+        );
+        assert_eq!(
+            normalized("wss://intiface.local:443/buttplug"),
+            "wss://intiface.local:443/buttplug"
+        );
+        assert_eq!(normalized("ws://[::1]"), "ws://[::1]:12345");
+        assert_eq!(normalized("ws://[::1]:54817"), "ws://[::1]:54817");
+    }
+
+    #[test]
+    fn normalize_server_rejects_bad_servers() {
+        for server in [
+            "localhost",
+            "localhost:12345",
+            "http://localhost:12345",
+            "https://localhost:12345",
+            "tcp://localhost:12345",
+            "ws://",
+            "ws://:12345",
+            "ws://[]:12345",
+            "ws://[::1:12345",
+            "ws://localhost:port",
+            "ws://localhost:123456",
+        ] {
+            assert!(
+                normalize_server(server).is_err(),
+                "{server} should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn uses_tls_follows_the_scheme() {
+        assert!(!uses_tls("ws://localhost:12345").unwrap());
+        assert!(!uses_tls("WS://localhost:12345").unwrap());
+        assert!(uses_tls("wss://intiface.local:443").unwrap());
+        assert!(uses_tls("localhost:12345").is_err());
+        assert!(uses_tls("http://localhost:12345").is_err());
+    }
+
+    #[test]
+    fn is_local_server_recognizes_this_machine() {
+        assert!(is_local_server("ws://localhost:12345"));
+        assert!(is_local_server("ws://127.0.0.1:12345/path"));
+        assert!(is_local_server("ws://[::1]:12345"));
+        assert!(is_local_server("localhost"));
+        assert!(!is_local_server("ws://intiface.local:12345"));
+        assert!(!is_local_server("ws://192.168.1.2:12345"));
+        assert!(!is_local_server("ws://[fe80::1]:12345"));
+    }
+
+    #[test]
+    fn allows_device_with_only_an_allow_regex() {
+        let args = Args::parse_from(["starplug", "--device-name-regex", "^Lovense"]);
+        assert!(args.allows_device("Lovense Hush"));
+        assert!(!args.allows_device("We-Vibe Sync"));
+    }
+
+    #[test]
+    fn allows_device_with_only_a_deny_regex() {
+        let args = Args::parse_from(["starplug", "--device-name-regex-deny", "Hush"]);
+        assert!(!args.allows_device("Lovense Hush"));
+        assert!(args.allows_device("Lovense Lush"));
+        assert!(args.allows_device("We-Vibe Sync"));
+    }
+
+    #[test]
+    fn allows_device_deny_wins_over_allow() {
+        let args = Args::parse_from([
+            "starplug",
+            "--device-name-regex",
+            "^Lovense",
+            "--device-name-regex-deny",
+            "Hush",
+        ]);
+        assert!(args.allows_device("Lovense Lush"));
+        assert!(!args.allows_device("Lovense Hush"));
+        assert!(!args.allows_device("We-Vibe Sync"));
+        assert!(Args::parse_from(["starplug"]).allows_device("We-Vibe Sync"));
+    }
+
+    #[test]
+    fn failure_counts_give_up_after_the_limit_in_a_row() {
+        let mut failures = FailureCounts::default();
+        for _ in 1..DEVICE_FAILURE_LIMIT {
+            assert!(!failures.record("Stroker", true));
+        }
+        // A success starts the count over.
+        assert!(!failures.record("Stroker", false));
+        for _ in 1..DEVICE_FAILURE_LIMIT {
+            assert!(!failures.record("Stroker", true));
+            assert!(!failures.record("Other", true));
+        }
+        assert!(failures.record("Stroker", true));
+        assert!(failures.record("Other", true));
+        // After giving up, a reconnected device gets a fresh count.
+        assert!(!failures.record("Stroker", true));
+    }
+
+    #[test]
+    fn zero_apm_watch_notices_long_runs_of_zeros_once() {
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+        let mut watch = ZeroApmWatch::default();
+        assert_eq!(watch.observe_at(0, at(0)), None);
+        assert_eq!(watch.observe_at(0, at(119)), None);
+        assert_eq!(watch.observe_at(0, at(120)), Some(ZeroApmNotice::LongRun));
+        assert_eq!(watch.observe_at(0, at(300)), None);
+        assert_eq!(
+            watch.observe_at(80, at(301)),
+            Some(ZeroApmNotice::Recovered)
+        );
+        assert_eq!(watch.observe_at(90, at(302)), None);
+
+        // Any nonzero APM starts the wait over.
+        assert_eq!(watch.observe_at(0, at(400)), None);
+        assert_eq!(watch.observe_at(10, at(500)), None);
+        assert_eq!(watch.observe_at(0, at(501)), None);
+        assert_eq!(watch.observe_at(0, at(600)), None);
+        assert_eq!(watch.observe_at(0, at(621)), Some(ZeroApmNotice::LongRun));
+    }
+
+    #[test]
+    fn rolling_apm_waits_for_a_full_window() {
+        let started = Instant::now();
+        let mut window = RollingApm {
+            window: Duration::from_secs(10),
+            started,
+            samples: VecDeque::new(),
+        };
+        assert_eq!(window.push_at(100, started), None);
+        assert_eq!(window.push_at(200, started + Duration::from_secs(5)), None);
+        assert_eq!(
+            window.push_at(300, started + Duration::from_secs(10)),
+            Some(200.0)
+        );
+    }
+
+    #[test]
+    fn rolling_apm_drops_samples_outside_the_window() {
+        let started = Instant::now();
+        let mut window = RollingApm {
+            window: Duration::from_secs(10),
+            started,
+            samples: VecDeque::new(),
+        };
+        window.push_at(100, started);
+        window.push_at(200, started + Duration::from_secs(5));
+        assert_eq!(
+            window.push_at(400, started + Duration::from_secs(12)),
+            Some(300.0)
+        );
+    }
+
+    fn ramp(rise_per_sec: Option<f64>, fall_per_sec: Option<f64>, updated: Instant) -> Ramp {
+        Ramp {
+            rise_per_sec,
+            fall_per_sec,
+            level: 0.0,
+            updated,
+        }
+    }
+
+    #[test]
+    fn ramp_limits_rises_and_falls() {
+        let start = Instant::now();
+        let mut ramp = ramp(Some(0.5), Some(0.25), start);
+        assert_eq!(ramp.update_at(1.0, start + Duration::from_secs(1)), 0.5);
+        assert!(ramp.is_moving(1.0));
+        assert_eq!(ramp.update_at(1.0, start + Duration::from_secs(3)), 1.0);
+        assert!(!ramp.is_moving(1.0));
+        assert_eq!(ramp.update_at(0.0, start + Duration::from_secs(5)), 0.5);
+        assert!(ramp.is_moving(0.0));
+    }
+
+    #[test]
+    fn ramp_without_a_rate_jumps() {
+        let start = Instant::now();
+        let mut ramp = ramp(None, Some(0.5), start);
+        assert_eq!(ramp.update_at(0.8, start), 0.8);
+        assert!(!ramp.is_moving(1.0));
+        assert!((ramp.update_at(0.0, start + Duration::from_secs(1)) - 0.3).abs() < 1e-12);
+        ramp.reset();
+        assert_eq!(ramp.level, 0.0);
+    }
+
+    #[test]
+    fn fade_out_goes_to_zero_over_its_duration() {
+        let started = Instant::now();
+        let fade = FadeOut {
+            from: 0.8,
+            duration: Duration::from_secs(4),
+            started,
+        };
+        assert_eq!(fade.level_at(started), 0.8);
+        assert!((fade.level_at(started + Duration::from_secs(1)) - 0.6).abs() < 1e-12);
+        assert_eq!(fade.level_at(started + Duration::from_secs(4)), 0.0);
+        assert_eq!(fade.level_at(started + Duration::from_secs(10)), 0.0);
+    }
+
+    #[test]
+    fn soft_start_starts_with_the_first_level() {
+        let start = Instant::now();
+        let mut soft_start = SoftStart::new(10.0);
+        assert!(!soft_start.is_running_at(start));
+        assert_eq!(
+            soft_start.scale_at(1.0, start + Duration::from_secs(30)),
+            0.0
+        );
+        assert!(soft_start.is_running_at(start + Duration::from_secs(30)));
+        assert_eq!(
+            soft_start.scale_at(1.0, start + Duration::from_secs(35)),
+            0.5
+        );
+        assert_eq!(
+            soft_start.scale_at(0.6, start + Duration::from_secs(40)),
+            0.6
+        );
+        assert!(!soft_start.is_running_at(start + Duration::from_secs(40)));
+        assert_eq!(
+            soft_start.scale_at(0.6, start + Duration::from_secs(50)),
+            0.6
+        );
+    }
+
+    fn subcommand_index_of(args: &[&str]) -> Option<usize> {
+        let args: Vec<OsString> = args.iter().map(OsString::from).collect();
+        subcommand_index(&Args::command(), &args)
+    }
+
+    #[test]
+    fn subcommand_index_skips_option_values() {
+        assert_eq!(subcommand_index_of(&["install-launchagent"]), Some(0));
+        assert_eq!(
+            subcommand_index_of(&["--min-apm", "80", "--once", "install-launchagent"]),
+            Some(3)
+        );
+        assert_eq!(
+            subcommand_index_of(&[
+                "--log-file",
+                "install-launchagent",
+                "install-launchagent",
+                "--min-apm=80"
+            ]),
+            Some(2)
+        );
+        assert_eq!(
+            subcommand_index_of(&["--log-file=install-launchagent", "install-launchagent"]),
+            Some(1)
+        );
+        assert_eq!(subcommand_index_of(&["--min-apm", "80"]), None);
+    }
+
+    #[test]
+    fn disabled_devices_stay_disabled_after_stopping() {
+        let output = Output::default();
+        output.disable_device("Lovense Hush");
+        output.stop_workers();
+        assert!(output.is_device_disabled("Lovense Hush"));
+        assert!(!output.is_device_disabled("Lovense Lush"));
+        output.enable_device("Lovense Hush");
+        assert!(!output.is_device_disabled("Lovense Hush"));
     }
 }
@@ -0,0 +1,881 @@
+//! Turn APM into vibration levels.
+//!
+//! Before any of this, with `--smoothing-alpha`, APM sources smooth raw APM with [`Ewma`].
+//! Then, with `--deadzone`, the loop that sends vibration commands holds APM at the min with
+//! [`Deadzone`] until it's clearly above it.
+//!
+//! Levels go through these stages, in this order:
+//!
+//! 1. **Map**: APM from `--min-apm` to `--max-apm` becomes a level from 0 to 1.
+//!    APM below the min is 0. APM above the max is more than 1, so later stages can tell how far over it is.
+//! 2. **Compress**: with `--compress-knee`, levels above the knee are smoothly squashed toward 1.
+//!    Levels at or below the knee are unchanged. Output is never negative.
+//! 3. **Clamp**: the level is limited to between 0 and 1.
+//! 4. **Curve**: with `--curve`, the level is reshaped. Curves map 0 to 0 and 1 to 1,
+//!    and the result is clamped again, so every level that leaves the mapper is between 0 and 1.
+//! 5. **Invert**: with `--invert`, the level becomes 1 minus the level, so APM at or below the min
+//!    gives full vibration and APM at or above the max gives none.
+//! 6. **Floor**: with `--floor`, the level is raised to the floor if it's below it.
+//!    The mapper is only used while a game is running, so stopping still brings devices to 0.
+//!
+//! With `--curve-points`, the table replaces stages 1 through 4: it already says where vibration starts and
+//! where it peaks, so `--min-apm`, `--max-apm`, `--compress-knee`, and `--curve` don't affect the level.
+//! Invert and floor still apply.
+//!
+//! Ramping (`--ramp-rate` and `--engagement-decay-ms`) is applied to the mapped level by the loop that sends
+//! vibration commands, since it depends on time.
+//! After that, [`ApmMapper::device_level`] applies each device's share of `--total-intensity-budget`,
+//! then its `--device-scale`, then the `--max-intensity` cap, which nothing sent to a device ever exceeds.
+
+use crate::Args;
+use std::str::FromStr;
+
+/// Steepness of the exponential curve when none is given.
+const DEFAULT_EXP_STEEPNESS: f64 = 3.0;
+
+/// Shape of the ramp from no vibration to full vibration.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Curve {
+    Linear,
+    /// Gentle through the mid-range, ramping up near the top.
+    Quadratic,
+    /// Ramps up quickly, then levels off near the top.
+    Sqrt,
+    /// Like quadratic, but steeper the higher the steepness.
+    Exponential {
+        steepness: f64,
+    },
+}
+
+impl Curve {
+    fn apply(self, level: f64) -> f64 {
+        match self {
+            Curve::Linear => level,
+            Curve::Quadratic => level * level,
+            Curve::Sqrt => level.sqrt(),
+            Curve::Exponential { steepness } => (steepness * level).exp_m1() / steepness.exp_m1(),
+        }
+    }
+}
+
+impl FromStr for Curve {
+    type Err = String;
+
+    /// Parse `linear`, `quadratic`, `sqrt`, `exp`, or `exp:<steepness>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, param) = match s.split_once(':') {
+            Some((name, param)) => (name, Some(param)),
+            None => (s, None),
+        };
+        let curve = match name {
+            "linear" => Curve::Linear,
+            "quadratic" => Curve::Quadratic,
+            "sqrt" => Curve::Sqrt,
+            "exp" | "exponential" => {
+                let steepness = match param {
+                    Some(param) => param
+                        .parse::<f64>()
+                        .map_err(|e| format!("Invalid steepness {param:?}: {e}"))?,
+                    None => DEFAULT_EXP_STEEPNESS,
+                };
+                if !(steepness > 0.0 && steepness.is_finite()) {
+                    return Err("Exponential curve steepness must be positive!".to_owned());
+                }
+                return Ok(Curve::Exponential { steepness });
+            }
+            _ => {
+                return Err(format!(
+                    "Unknown curve {name:?}: expected linear, quadratic, sqrt, or exp[:steepness]"
+                ))
+            }
+        };
+        if param.is_some() {
+            return Err(format!("The {name} curve doesn't take a parameter"));
+        }
+        Ok(curve)
+    }
+}
+
+/// Piecewise-linear APM to level table, parsed from `<apm>:<level>,<apm>:<level>,…`.
+/// APM outside the table gets the level at the nearest end.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CurvePoints(Vec<(i32, f64)>);
+
+impl CurvePoints {
+    fn level(&self, apm: i32) -> f64 {
+        let points = &self.0;
+        let (first_apm, first_level) = points[0];
+        if apm <= first_apm {
+            return first_level;
+        }
+        for pair in points.windows(2) {
+            let [(low_apm, low_level), (high_apm, high_level)] = [pair[0], pair[1]];
+            if apm <= high_apm {
+                let t = (apm - low_apm) as f64 / (high_apm - low_apm) as f64;
+                return low_level + t * (high_level - low_level);
+            }
+        }
+        points[points.len() - 1].1
+    }
+}
+
+impl FromStr for CurvePoints {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut points: Vec<(i32, f64)> = vec![];
+        for point in s.split(',') {
+            let Some((apm, level)) = point.split_once(':') else {
+                return Err(format!("Expected <apm>:<level>, got {point:?}"));
+            };
+            let apm = apm
+                .trim()
+                .parse::<i32>()
+                .map_err(|e| format!("Invalid APM {apm:?}: {e}"))?;
+            let level = level
+                .trim()
+                .parse::<f64>()
+                .map_err(|e| format!("Invalid level {level:?}: {e}"))?;
+            if !(0.0..=1.0).contains(&level) {
+                return Err(format!("Level for {apm} APM must be between 0 and 1!"));
+            }
+            if let Some(&(prev_apm, _)) = points.last() {
+                if apm <= prev_apm {
+                    return Err(format!(
+                        "APM must increase from point to point, but {apm} comes after {prev_apm}!"
+                    ));
+                }
+            }
+            points.push((apm, level));
+        }
+        if points.len() < 2 {
+            return Err("Curve points need at least two points!".to_owned());
+        }
+        Ok(Self(points))
+    }
+}
+
+/// Thresholds and curve for one actuator type, from an `[actuators.<type>]` table in the config file.
+/// Anything not given falls back to the global setting.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ActuatorThresholds {
+    pub min_apm: Option<i32>,
+    pub max_apm: Option<i32>,
+    pub curve: Option<Curve>,
+}
+
+impl ActuatorThresholds {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Settings for the APM to level stages.
+#[derive(Clone, Debug)]
+pub struct ApmMapper {
+    min_apm: i32,
+    max_apm: i32,
+    compress_knee: Option<f64>,
+    curve: Curve,
+    curve_points: Option<CurvePoints>,
+    invert: bool,
+    floor: f64,
+    total_intensity_budget: Option<f64>,
+    max_intensity: f64,
+}
+
+impl ApmMapper {
+    pub fn new(args: &Args) -> Self {
+        Self {
+            min_apm: args.min_apm,
+            max_apm: args.max_apm,
+            compress_knee: args.compress_knee,
+            curve: args.curve,
+            curve_points: args.curve_points.clone(),
+            invert: args.invert,
+            floor: args.floor,
+            total_intensity_budget: args.total_intensity_budget,
+            max_intensity: args.max_intensity,
+        }
+    }
+
+    /// Run APM through every stage, producing a level between 0 and 1.
+    pub fn level(&self, apm: i32) -> f64 {
+        let level = match &self.curve_points {
+            Some(curve_points) => curve_points.level(apm),
+            None => {
+                let level = self.map(apm);
+                let level = match self.compress_knee {
+                    Some(knee) => compress(level, knee),
+                    None => level,
+                };
+                let level = clamp(level);
+                clamp(self.curve.apply(level))
+            }
+        };
+        let level = if self.invert { 1.0 - level } else { level };
+        level.max(self.floor)
+    }
+
+    /// Level for one of `devices` devices: limited so they add up to no more than the budget,
+    /// multiplied by the device's scale, and capped at the max intensity.
+    /// Output is between 0 and the input level.
+    pub fn device_level(&self, level: f64, devices: usize, scale: f64) -> f64 {
+        let level = match self.total_intensity_budget {
+            Some(budget) if devices > 0 => level.min(budget / devices as f64),
+            _ => level,
+        };
+        self.cap(level * scale)
+    }
+
+    /// Base level for a device with a `--device-band`, before [`ApmMapper::device_level`].
+    /// APM is mapped with the band's own min and max, and gets 0 above the band as well as below it.
+    /// `--curve-points`, `--invert`, and `--floor` don't apply to bands.
+    pub fn band_level(&self, band: &DeviceBand, apm: i32) -> f64 {
+        if apm > band.max_apm {
+            return 0.0;
+        }
+        let mapper = ApmMapper {
+            min_apm: band.min_apm,
+            max_apm: band.max_apm,
+            curve_points: None,
+            invert: false,
+            floor: 0.0,
+            ..self.clone()
+        };
+        mapper.level(apm)
+    }
+
+    /// This mapper with one actuator type's own thresholds and curve.
+    /// `--curve-points` don't apply once a type has thresholds of its own, since they're in APM.
+    pub fn with_thresholds(&self, thresholds: &ActuatorThresholds) -> Self {
+        if thresholds.is_empty() {
+            return self.clone();
+        }
+        ApmMapper {
+            min_apm: thresholds.min_apm.unwrap_or(self.min_apm),
+            max_apm: thresholds.max_apm.unwrap_or(self.max_apm),
+            curve: thresholds.curve.unwrap_or(self.curve),
+            curve_points: None,
+            ..self.clone()
+        }
+    }
+
+    /// Keep a level between 0 and the max intensity.
+    pub fn cap(&self, level: f64) -> f64 {
+        clamp(level).min(self.max_intensity)
+    }
+
+    /// Map stage: 0 at or below the min APM, 1 at the max APM, and more than 1 above it.
+    fn map(&self, apm: i32) -> f64 {
+        let apm_range = (self.max_apm - self.min_apm) as f64;
+        ((apm - self.min_apm) as f64 / apm_range).max(0f64)
+    }
+}
+
+/// Compress stage: leave levels below the knee alone, and smoothly squash everything above it
+/// into the space between the knee and 1, so that higher APM always feels a little stronger.
+fn compress(level: f64, knee: f64) -> f64 {
+    if level <= knee {
+        return level;
+    }
+    let headroom = 1.0 - knee;
+    knee + headroom * (1.0 - (-(level - knee) / headroom).exp())
+}
+
+/// Clamp stage: keep levels between 0 and 1.
+fn clamp(level: f64) -> f64 {
+    level.clamp(0f64, 1f64)
+}
+
+/// Exponentially weighted moving average of APM, to keep vibration from jumping around.
+pub struct Ewma {
+    /// How much of each new value to take, from 0 to 1. 0 turns smoothing off.
+    alpha: f64,
+    average: Option<f64>,
+}
+
+impl Ewma {
+    pub fn new(alpha: f64) -> Self {
+        Self {
+            alpha,
+            average: None,
+        }
+    }
+
+    /// Add a raw APM value and get the smoothed APM.
+    pub fn update(&mut self, apm: i32) -> i32 {
+        if self.alpha == 0.0 {
+            return apm;
+        }
+        let average = match self.average {
+            Some(average) => average + self.alpha * (apm as f64 - average),
+            None => apm as f64,
+        };
+        self.average = Some(average);
+        average.round() as i32
+    }
+}
+
+/// Percentile of APM samples that `--calibrate` suggests as the min APM.
+const CALIBRATION_MIN_PERCENTILE: f64 = 0.2;
+
+/// Percentile of APM samples that `--calibrate` suggests as the max APM.
+const CALIBRATION_MAX_PERCENTILE: f64 = 0.9;
+
+/// Suggest a min and max APM from APM recorded during play, for `--calibrate`.
+/// The max is always strictly greater than the min. `None` if there are no samples.
+pub fn suggest_apm_range(samples: &[i32]) -> Option<(i32, i32)> {
+    if samples.is_empty() {
+        return None;
+    }
+    let mut sorted = samples.to_vec();
+    sorted.sort_unstable();
+    // Nearest-rank percentile.
+    let percentile = |fraction: f64| {
+        let rank = (fraction * sorted.len() as f64).ceil() as usize;
+        sorted[rank.clamp(1, sorted.len()) - 1]
+    };
+    let min_apm = percentile(CALIBRATION_MIN_PERCENTILE);
+    let max_apm = percentile(CALIBRATION_MAX_PERCENTILE).max(min_apm + 1);
+    Some((min_apm, max_apm))
+}
+
+/// Hysteresis around the min APM, so APM hovering right at it doesn't flick vibration on and off.
+/// Vibration starts once APM is over the min plus the deadzone, and stops once it's below the min.
+pub struct Deadzone {
+    min_apm: i32,
+    deadzone: i32,
+    on: bool,
+}
+
+impl Deadzone {
+    pub fn new(min_apm: i32, deadzone: i32) -> Self {
+        Self {
+            min_apm,
+            deadzone,
+            on: false,
+        }
+    }
+
+    /// Get the APM to map: the min APM while off, and the APM itself while on.
+    pub fn update(&mut self, apm: i32) -> i32 {
+        if self.on {
+            self.on = apm >= self.min_apm;
+        } else {
+            self.on = apm > self.min_apm + self.deadzone;
+        }
+        if self.on {
+            apm
+        } else {
+            apm.min(self.min_apm)
+        }
+    }
+
+    /// Start off again, as at the start of a game.
+    pub fn reset(&mut self) {
+        self.on = false;
+    }
+}
+
+/// Multiplier for the level sent to one device, parsed from `<device name>=<scale>`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceScale {
+    pub name: String,
+    pub scale: f64,
+}
+
+impl FromStr for DeviceScale {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((name, scale)) = s.rsplit_once('=') else {
+            return Err(format!("Expected <device name>=<scale>, got {s:?}"));
+        };
+        let scale = scale
+            .trim()
+            .parse::<f64>()
+            .map_err(|e| format!("Invalid scale {scale:?}: {e}"))?;
+        if !(0.0..=1.0).contains(&scale) {
+            return Err("Device scale must be between 0 and 1!".to_owned());
+        }
+        Ok(Self {
+            name: name.trim().to_owned(),
+            scale,
+        })
+    }
+}
+
+/// APM range that one device follows on its own, parsed from `<device name>=<min>-<max>`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceBand {
+    pub name: String,
+    pub min_apm: i32,
+    pub max_apm: i32,
+}
+
+impl FromStr for DeviceBand {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((name, band)) = s.rsplit_once('=') else {
+            return Err(format!("Expected <device name>=<min>-<max>, got {s:?}"));
+        };
+        let Some((min_apm, max_apm)) = band.split_once('-') else {
+            return Err(format!("Expected an APM range like 40-90, got {band:?}"));
+        };
+        let parse = |apm: &str| {
+            apm.trim()
+                .parse::<i32>()
+                .map_err(|e| format!("Invalid APM {apm:?}: {e}"))
+        };
+        let (min_apm, max_apm) = (parse(min_apm)?, parse(max_apm)?);
+        if min_apm >= max_apm {
+            return Err("A device band's max APM must be greater than its min APM!".to_owned());
+        }
+        Ok(Self {
+            name: name.trim().to_owned(),
+            min_apm,
+            max_apm,
+        })
+    }
+}
+
+/// What one motor of a multi-motor device does, for `--actuator-map`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ActuatorRole {
+    /// Follow APM like single-motor devices do.
+    Follow,
+    /// Hold this level whenever the device is vibrating.
+    Fixed(f64),
+}
+
+/// Role for one actuator index, parsed from `<index>=follow` or `<index>=fixed:<level>`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ActuatorMapping {
+    pub index: u32,
+    pub role: ActuatorRole,
+}
+
+impl FromStr for ActuatorMapping {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((index, role)) = s.split_once('=') else {
+            return Err(format!(
+                "Expected <index>=follow or <index>=fixed:<level>, got {s:?}"
+            ));
+        };
+        let index = index
+            .trim()
+            .parse::<u32>()
+            .map_err(|e| format!("Invalid actuator index {index:?}: {e}"))?;
+        let role = match role.trim().split_once(':') {
+            None if role.trim() == "follow" => ActuatorRole::Follow,
+            Some(("fixed", level)) => {
+                let level = level
+                    .trim()
+                    .parse::<f64>()
+                    .map_err(|e| format!("Invalid fixed level {level:?}: {e}"))?;
+                if !(0.0..=1.0).contains(&level) {
+                    return Err("Fixed actuator level must be between 0 and 1!".to_owned());
+                }
+                ActuatorRole::Fixed(level)
+            }
+            _ => {
+                return Err(format!(
+                    "Unknown actuator role {role:?}: expected follow or fixed:<level>"
+                ))
+            }
+        };
+        Ok(Self { index, role })
+    }
+}
+
+/// Level for one actuator of a device that's being sent `level`.
+/// Actuators not in the map follow APM.
+pub fn actuator_level(actuator_map: &[ActuatorMapping], index: u32, level: f64) -> f64 {
+    match actuator_map.iter().find(|mapping| mapping.index == index) {
+        Some(ActuatorMapping {
+            role: ActuatorRole::Fixed(fixed),
+            ..
+        }) => *fixed,
+        _ => level,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapper(compress_knee: Option<f64>) -> ApmMapper {
+        ApmMapper {
+            min_apm: 60,
+            max_apm: 120,
+            compress_knee,
+            curve: Curve::Linear,
+            curve_points: None,
+            invert: false,
+            floor: 0.0,
+            total_intensity_budget: None,
+            max_intensity: 1.0,
+        }
+    }
+
+    fn budget_mapper(total_intensity_budget: Option<f64>) -> ApmMapper {
+        ApmMapper {
+            total_intensity_budget,
+            ..mapper(None)
+        }
+    }
+
+    #[test]
+    fn map_is_linear_between_min_and_max() {
+        let mapper = mapper(None);
+        assert_eq!(mapper.map(60), 0.0);
+        assert_eq!(mapper.map(90), 0.5);
+        assert_eq!(mapper.map(120), 1.0);
+    }
+
+    #[test]
+    fn map_is_zero_below_min_and_unclamped_above_max() {
+        let mapper = mapper(None);
+        assert_eq!(mapper.map(0), 0.0);
+        assert_eq!(mapper.map(180), 2.0);
+    }
+
+    #[test]
+    fn compress_leaves_levels_below_knee_alone() {
+        assert_eq!(compress(0.0, 0.8), 0.0);
+        assert_eq!(compress(0.5, 0.8), 0.5);
+        assert_eq!(compress(0.8, 0.8), 0.8);
+    }
+
+    #[test]
+    fn compress_keeps_rising_without_reaching_one() {
+        let a = compress(1.0, 0.8);
+        let b = compress(2.0, 0.8);
+        assert!(0.8 < a && a < b && b < 1.0);
+    }
+
+    #[test]
+    fn clamp_limits_to_unit_range() {
+        assert_eq!(clamp(-0.5), 0.0);
+        assert_eq!(clamp(0.25), 0.25);
+        assert_eq!(clamp(1.5), 1.0);
+    }
+
+    #[test]
+    fn level_is_always_in_unit_range() {
+        for mapper in [mapper(None), mapper(Some(0.8))] {
+            for apm in [-100, 0, 60, 90, 120, 500, i32::MAX / 2] {
+                let level = mapper.level(apm);
+                assert!((0.0..=1.0).contains(&level), "{apm} → {level}");
+            }
+        }
+    }
+
+    #[test]
+    fn level_is_zero_at_min_apm() {
+        assert_eq!(mapper(None).level(60), 0.0);
+        assert_eq!(mapper(Some(0.8)).level(60), 0.0);
+    }
+
+    #[test]
+    fn level_clamps_outside_min_and_max() {
+        let mapper = mapper(None);
+        assert_eq!(mapper.level(0), 0.0);
+        assert_eq!(mapper.level(59), 0.0);
+        assert_eq!(mapper.level(120), 1.0);
+        assert_eq!(mapper.level(121), 1.0);
+        assert_eq!(mapper.level(10_000), 1.0);
+    }
+
+    #[test]
+    fn level_handles_a_one_apm_range() {
+        let mapper = ApmMapper {
+            max_apm: 61,
+            ..mapper(None)
+        };
+        assert_eq!(mapper.level(59), 0.0);
+        assert_eq!(mapper.level(60), 0.0);
+        assert_eq!(mapper.level(61), 1.0);
+        assert_eq!(mapper.level(62), 1.0);
+    }
+
+    #[test]
+    fn device_level_splits_budget() {
+        assert_eq!(budget_mapper(Some(1.0)).device_level(1.0, 2, 1.0), 0.5);
+        assert_eq!(budget_mapper(Some(1.0)).device_level(0.25, 2, 1.0), 0.25);
+        assert_eq!(budget_mapper(None).device_level(0.75, 2, 1.0), 0.75);
+        assert_eq!(budget_mapper(Some(1.0)).device_level(0.75, 0, 1.0), 0.75);
+    }
+
+    #[test]
+    fn device_level_scales_after_budget() {
+        assert_eq!(budget_mapper(None).device_level(1.0, 2, 0.5), 0.5);
+        assert_eq!(budget_mapper(Some(1.0)).device_level(1.0, 2, 0.5), 0.25);
+        assert_eq!(budget_mapper(None).device_level(0.5, 1, 0.0), 0.0);
+    }
+
+    #[test]
+    fn device_level_never_exceeds_max_intensity() {
+        let mapper = ApmMapper {
+            max_intensity: 0.7,
+            ..mapper(None)
+        };
+        assert_eq!(mapper.device_level(1.0, 1, 1.0), 0.7);
+        assert_eq!(mapper.device_level(0.5, 1, 1.0), 0.5);
+        assert_eq!(mapper.device_level(1.0, 2, 0.5), 0.5);
+    }
+
+    #[test]
+    fn device_scale_parsing() {
+        assert_eq!(
+            "Lovense Hush=0.6".parse::<DeviceScale>(),
+            Ok(DeviceScale {
+                name: "Lovense Hush".to_owned(),
+                scale: 0.6
+            })
+        );
+        assert!("Lovense Hush".parse::<DeviceScale>().is_err());
+        assert!("Lovense Hush=loud".parse::<DeviceScale>().is_err());
+        assert!("Lovense Hush=1.5".parse::<DeviceScale>().is_err());
+    }
+
+    #[test]
+    fn device_band_parsing() {
+        assert_eq!(
+            "Toy A=40-90".parse::<DeviceBand>(),
+            Ok(DeviceBand {
+                name: "Toy A".to_owned(),
+                min_apm: 40,
+                max_apm: 90
+            })
+        );
+        assert!("Toy A".parse::<DeviceBand>().is_err());
+        assert!("Toy A=40".parse::<DeviceBand>().is_err());
+        assert!("Toy A=90-40".parse::<DeviceBand>().is_err());
+        assert!("Toy A=-40-90".parse::<DeviceBand>().is_err());
+    }
+
+    #[test]
+    fn band_level_uses_the_band_and_is_zero_outside_it() {
+        let mapper = mapper(None);
+        let band = DeviceBand {
+            name: "Toy A".to_owned(),
+            min_apm: 40,
+            max_apm: 90,
+        };
+        assert_eq!(mapper.band_level(&band, 30), 0.0);
+        assert_eq!(mapper.band_level(&band, 40), 0.0);
+        assert_eq!(mapper.band_level(&band, 65), 0.5);
+        assert_eq!(mapper.band_level(&band, 90), 1.0);
+        assert_eq!(mapper.band_level(&band, 91), 0.0);
+    }
+
+    #[test]
+    fn band_level_ignores_floor_and_invert() {
+        let mapper = ApmMapper {
+            invert: true,
+            floor: 0.2,
+            ..mapper(None)
+        };
+        let band = DeviceBand {
+            name: "Toy A".to_owned(),
+            min_apm: 40,
+            max_apm: 90,
+        };
+        assert_eq!(mapper.band_level(&band, 30), 0.0);
+        assert_eq!(mapper.band_level(&band, 90), 1.0);
+    }
+
+    #[test]
+    fn with_thresholds_replaces_only_what_is_given() {
+        let mapper = mapper(None);
+        let rotate = mapper.with_thresholds(&ActuatorThresholds {
+            min_apm: Some(90),
+            max_apm: None,
+            curve: Some(Curve::Quadratic),
+        });
+        assert_eq!(rotate.level(90), 0.0);
+        assert_eq!(rotate.level(105), 0.25);
+        assert_eq!(rotate.level(120), 1.0);
+        assert_eq!(
+            mapper
+                .with_thresholds(&ActuatorThresholds::default())
+                .level(90),
+            0.5
+        );
+    }
+
+    #[test]
+    fn actuator_map_parsing() {
+        assert_eq!(
+            "0=follow".parse::<ActuatorMapping>(),
+            Ok(ActuatorMapping {
+                index: 0,
+                role: ActuatorRole::Follow
+            })
+        );
+        assert_eq!(
+            "1=fixed:0.3".parse::<ActuatorMapping>(),
+            Ok(ActuatorMapping {
+                index: 1,
+                role: ActuatorRole::Fixed(0.3)
+            })
+        );
+        assert!("follow".parse::<ActuatorMapping>().is_err());
+        assert!("front=follow".parse::<ActuatorMapping>().is_err());
+        assert!("1=fixed".parse::<ActuatorMapping>().is_err());
+        assert!("1=fixed:2".parse::<ActuatorMapping>().is_err());
+        assert!("1=wiggle".parse::<ActuatorMapping>().is_err());
+    }
+
+    #[test]
+    fn actuator_levels_follow_unless_fixed() {
+        let actuator_map = ["0=follow".parse().unwrap(), "1=fixed:0.3".parse().unwrap()];
+        assert_eq!(actuator_level(&actuator_map, 0, 0.8), 0.8);
+        assert_eq!(actuator_level(&actuator_map, 1, 0.8), 0.3);
+        assert_eq!(actuator_level(&actuator_map, 2, 0.8), 0.8);
+        assert_eq!(actuator_level(&[], 1, 0.8), 0.8);
+    }
+
+    #[test]
+    fn calibration_uses_percentiles() {
+        let samples: Vec<i32> = (1..=100).rev().collect();
+        assert_eq!(suggest_apm_range(&samples), Some((20, 90)));
+    }
+
+    #[test]
+    fn calibration_keeps_max_above_min() {
+        assert_eq!(suggest_apm_range(&[80, 80, 80]), Some((80, 81)));
+        assert_eq!(suggest_apm_range(&[42]), Some((42, 43)));
+        assert_eq!(suggest_apm_range(&[]), None);
+    }
+
+    #[test]
+    fn curve_points_interpolate() {
+        let mapper = ApmMapper {
+            curve_points: Some("40:0.0,80:0.4,120:0.9,160:1.0".parse().unwrap()),
+            ..mapper(None)
+        };
+        assert_eq!(mapper.level(0), 0.0);
+        assert_eq!(mapper.level(40), 0.0);
+        assert!((mapper.level(60) - 0.2).abs() < 1e-12);
+        assert_eq!(mapper.level(80), 0.4);
+        assert!((mapper.level(100) - 0.65).abs() < 1e-12);
+        assert_eq!(mapper.level(160), 1.0);
+        assert_eq!(mapper.level(500), 1.0);
+    }
+
+    #[test]
+    fn curve_points_parsing_rejects_bad_input() {
+        assert!("40:0.0".parse::<CurvePoints>().is_err());
+        assert!("80:0.4,40:0.0".parse::<CurvePoints>().is_err());
+        assert!("40:0.0,40:0.5".parse::<CurvePoints>().is_err());
+        assert!("40:0.0,80:1.5".parse::<CurvePoints>().is_err());
+        assert!("40:0.0,eighty:0.4".parse::<CurvePoints>().is_err());
+        assert!("40,80".parse::<CurvePoints>().is_err());
+    }
+
+    #[test]
+    fn curves_keep_endpoints() {
+        for curve in ["linear", "quadratic", "sqrt", "exp", "exp:0.5", "exp:10"] {
+            let curve: Curve = curve.parse().unwrap();
+            assert_eq!(curve.apply(0.0), 0.0, "{curve:?}");
+            assert!((curve.apply(1.0) - 1.0).abs() < 1e-12, "{curve:?}");
+        }
+    }
+
+    #[test]
+    fn curves_shape_the_middle() {
+        assert_eq!(Curve::Quadratic.apply(0.5), 0.25);
+        assert_eq!(Curve::Sqrt.apply(0.25), 0.5);
+        assert!(Curve::Exponential { steepness: 2.5 }.apply(0.5) < 0.5);
+    }
+
+    #[test]
+    fn curve_parsing_rejects_bad_input() {
+        assert_eq!(
+            "exp:2.5".parse::<Curve>(),
+            Ok(Curve::Exponential { steepness: 2.5 })
+        );
+        assert!("exp:0".parse::<Curve>().is_err());
+        assert!("exp:steep".parse::<Curve>().is_err());
+        assert!("sqrt:2".parse::<Curve>().is_err());
+        assert!("cubic".parse::<Curve>().is_err());
+    }
+
+    #[test]
+    fn ewma_off_passes_apm_through() {
+        let mut ewma = Ewma::new(0.0);
+        assert_eq!(ewma.update(100), 100);
+        assert_eq!(ewma.update(200), 200);
+    }
+
+    #[test]
+    fn ewma_moves_part_way_toward_new_values() {
+        let mut ewma = Ewma::new(0.5);
+        assert_eq!(ewma.update(100), 100);
+        assert_eq!(ewma.update(200), 150);
+        assert_eq!(ewma.update(200), 175);
+    }
+
+    #[test]
+    fn deadzone_starts_above_it_and_stops_below_min() {
+        let mut deadzone = Deadzone::new(60, 10);
+        assert_eq!(deadzone.update(65), 60);
+        assert_eq!(deadzone.update(70), 60);
+        assert_eq!(deadzone.update(71), 71);
+        assert_eq!(deadzone.update(62), 62);
+        assert_eq!(deadzone.update(60), 60);
+        assert_eq!(deadzone.update(59), 59);
+        assert_eq!(deadzone.update(65), 60);
+    }
+
+    #[test]
+    fn deadzone_of_zero_passes_apm_through() {
+        let mut deadzone = Deadzone::new(60, 0);
+        for apm in [0, 59, 60, 61, 120, 30] {
+            assert_eq!(deadzone.update(apm), apm);
+        }
+    }
+
+    #[test]
+    fn floor_raises_low_levels_only() {
+        let mapper = ApmMapper {
+            floor: 0.2,
+            ..mapper(None)
+        };
+        assert_eq!(mapper.level(0), 0.2);
+        assert_eq!(mapper.level(60), 0.2);
+        assert_eq!(mapper.level(90), 0.5);
+        assert_eq!(mapper.level(120), 1.0);
+    }
+
+    #[test]
+    fn invert_flips_the_range() {
+        let mapper = ApmMapper {
+            invert: true,
+            ..mapper(None)
+        };
+        assert_eq!(mapper.level(0), 1.0);
+        assert_eq!(mapper.level(60), 1.0);
+        assert_eq!(mapper.level(90), 0.5);
+        assert_eq!(mapper.level(120), 0.0);
+        assert_eq!(mapper.level(500), 0.0);
+    }
+
+    #[test]
+    fn invert_composes_with_floor_and_cap() {
+        let mapper = ApmMapper {
+            invert: true,
+            floor: 0.2,
+            max_intensity: 0.8,
+            ..mapper(None)
+        };
+        assert_eq!(mapper.device_level(mapper.level(60), 1, 1.0), 0.8);
+        assert_eq!(mapper.device_level(mapper.level(120), 1, 1.0), 0.2);
+    }
+}
@@ -0,0 +1,262 @@
+//! Serve Prometheus metrics, for dashboards.
+
+use crate::events::{Events, State};
+use anyhow::{anyhow, Result};
+use std::fmt::Write as _;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+use tracing::{info, warn};
+
+/// Wait this long before accepting again after a failed accept,
+/// so an error that doesn't go away, like running out of file descriptors, doesn't spin.
+const ACCEPT_RETRY_WAIT: Duration = Duration::from_millis(100);
+
+/// Handle to the metrics server.
+pub struct MetricsServer {
+    server: JoinHandle<()>,
+    updater: JoinHandle<()>,
+}
+
+impl MetricsServer {
+    /// Stop serving metrics.
+    pub fn close(self) {
+        self.server.abort();
+        self.updater.abort();
+    }
+}
+
+/// Start serving metrics at `http://<addr>/metrics`.
+pub async fn spawn_metrics_server(events: &Events, addr: &str) -> Result<MetricsServer> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .map_err(|e| anyhow!(e).context(format!("Couldn't serve metrics on {addr}")))?;
+    info!("Serving metrics at http://{addr}/metrics");
+
+    let state = Arc::new(Mutex::new(State::default()));
+
+    let mut rx = events.subscribe();
+    let updater_state = state.clone();
+    let updater = tokio::spawn(async move {
+        loop {
+            match rx.recv().await {
+                Ok(event) => updater_state.lock().unwrap().apply(&event),
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+
+    let server = tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!("Couldn't accept metrics connection: {e}");
+                    sleep(ACCEPT_RETRY_WAIT).await;
+                    continue;
+                }
+            };
+            let body = render(&state.lock().unwrap());
+            tokio::spawn(async move {
+                if let Err(e) = respond(stream, &body).await {
+                    warn!("Couldn't serve metrics: {e}");
+                }
+            });
+        }
+    });
+
+    Ok(MetricsServer { server, updater })
+}
+
+/// Format the state in the Prometheus text exposition format.
+fn render(state: &State) -> String {
+    let mut body = String::new();
+    for (name, help, value) in [
+        ("starplug_apm", "Current APM.", state.apm as f64),
+        (
+            "starplug_intensity",
+            "Current vibration level, from 0 to 1.",
+            state.level,
+        ),
+        (
+            "starplug_intiface_connected",
+            "1 if connected to Intiface, 0 if not.",
+            state.connected as u8 as f64,
+        ),
+        (
+            "starplug_starcraft_connected",
+            "1 if StarCraft is sending APM, 0 if not.",
+            state.game_running as u8 as f64,
+        ),
+        (
+            "starplug_devices",
+            "Number of connected devices that StarPlug is driving.",
+            state.driven_devices().count() as f64,
+        ),
+    ] {
+        let _ = writeln!(body, "# HELP {name} {help}");
+        let _ = writeln!(body, "# TYPE {name} gauge");
+        let _ = writeln!(body, "{name} {value}");
+    }
+    if !state.batteries.is_empty() {
+        let _ = writeln!(
+            body,
+            "# HELP starplug_battery_level Battery level of each device that reports one, from 0 to 1."
+        );
+        let _ = writeln!(body, "# TYPE starplug_battery_level gauge");
+        for (device, level) in &state.batteries {
+            let device = device.replace('\\', "\\\\").replace('"', "\\\"");
+            let _ = writeln!(
+                body,
+                "starplug_battery_level{{device=\"{device}\"}} {level}"
+            );
+        }
+    }
+    body
+}
+
+/// Answer one HTTP request. Anything other than `GET /metrics` gets a 404.
+async fn respond(mut stream: TcpStream, body: &str) -> Result<()> {
+    // The request line is all we need, and it fits in one read.
+    let mut request = [0u8; 1024];
+    let len = stream.read(&mut request).await?;
+    let request = String::from_utf8_lossy(&request[..len]);
+    let is_metrics = request
+        .lines()
+        .next()
+        .is_some_and(|line| line.starts_with("GET /metrics "));
+
+    let response = if is_metrics {
+        format!(
+            "HTTP/1.1 200 OK\r\n\
+            Content-Type: text/plain; version=0.0.4\r\n\
+            Content-Length: {len}\r\n\
+            Connection: close\r\n\r\n{body}",
+            len = body.len()
+        )
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_owned()
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::Event;
+
+    fn device_added(server: &str, name: &str, driven: bool) -> Event {
+        Event::DeviceAdded {
+            server: server.to_owned(),
+            name: name.to_owned(),
+            driven,
+        }
+    }
+
+    fn state(events: &[Event]) -> State {
+        let mut state = State::default();
+        for event in events {
+            state.apply(event);
+        }
+        state
+    }
+
+    #[test]
+    fn render_writes_each_gauge() {
+        let body = render(&state(&[
+            Event::IntifaceConnected {
+                server: "ws://localhost:12345".to_owned(),
+            },
+            Event::GameStarted,
+            Event::Apm {
+                apm: 120,
+                level: 0.5,
+            },
+        ]));
+        for line in [
+            "# HELP starplug_apm Current APM.",
+            "# TYPE starplug_apm gauge",
+            "starplug_apm 120",
+            "starplug_intensity 0.5",
+            "starplug_intiface_connected 1",
+            "starplug_starcraft_connected 1",
+            "starplug_devices 0",
+        ] {
+            assert!(
+                body.lines().any(|l| l == line),
+                "missing {line:?} in:\n{body}"
+            );
+        }
+        assert!(!body.contains("starplug_battery_level"));
+    }
+
+    #[test]
+    fn render_counts_only_driven_devices() {
+        let body = render(&state(&[
+            device_added("ws://a:12345", "Lovense Hush", true),
+            device_added("ws://a:12345", "Lovense Max", false),
+            device_added("ws://b:12345", "Lovense Hush", true),
+        ]));
+        assert!(body.lines().any(|l| l == "starplug_devices 2"), "{body}");
+    }
+
+    #[test]
+    fn render_escapes_battery_labels() {
+        let body = render(&state(&[
+            device_added("ws://a:12345", "Toy \"A\"", true),
+            Event::Battery {
+                name: "Toy \"A\"".to_owned(),
+                level: 0.25,
+            },
+        ]));
+        assert!(
+            body.lines()
+                .any(|l| l == "starplug_battery_level{device=\"Toy \\\"A\\\"\"} 0.25"),
+            "{body}"
+        );
+    }
+
+    #[test]
+    fn disconnecting_one_server_keeps_the_others_devices() {
+        let state = state(&[
+            Event::IntifaceConnected {
+                server: "ws://a:12345".to_owned(),
+            },
+            Event::IntifaceConnected {
+                server: "ws://b:12345".to_owned(),
+            },
+            device_added("ws://a:12345", "Lovense Hush", true),
+            device_added("ws://b:12345", "Lovense Lush", true),
+            Event::Battery {
+                name: "Lovense Hush".to_owned(),
+                level: 0.5,
+            },
+            Event::Battery {
+                name: "Lovense Lush".to_owned(),
+                level: 0.5,
+            },
+            Event::IntifaceDisconnected {
+                server: "ws://a:12345".to_owned(),
+            },
+        ]);
+        assert!(state.connected);
+        let names: Vec<&str> = state
+            .devices
+            .iter()
+            .map(|device| device.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["Lovense Lush"]);
+        assert!(!state.batteries.contains_key("Lovense Hush"));
+        assert!(state.batteries.contains_key("Lovense Lush"));
+        assert!(render(&state)
+            .lines()
+            .any(|l| l == "starplug_intiface_connected 1"));
+    }
+}
@@ -0,0 +1,164 @@
+//! Send APM and vibration levels as OSC messages, for VRChat avatars, visualizers, and the like.
+
+use crate::events::{Event, EventSink};
+use anyhow::{anyhow, bail, Result};
+use rosc::{OscMessage, OscPacket, OscType};
+use std::io::ErrorKind;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use tracing::warn;
+
+/// Address path that messages go under when a destination doesn't give its own.
+const DEFAULT_PATH: &str = "/starplug";
+
+/// One place to send OSC messages, with its own socket so a dead one can't hold up the others.
+struct Destination {
+    socket: UdpSocket,
+    addr: SocketAddr,
+    /// Messages go to `<path>/apm` and `<path>/level`.
+    path: String,
+    warned: bool,
+}
+
+impl Destination {
+    /// Set up `host:port`, or `host:port/path` to send under a path other than `/starplug`.
+    fn new(destination: &str) -> Result<Self> {
+        let (host_port, path) = match destination.find('/') {
+            Some(split) => (
+                &destination[..split],
+                destination[split..].trim_end_matches('/'),
+            ),
+            None => (destination, DEFAULT_PATH),
+        };
+        if path.is_empty() {
+            bail!("OSC destination {destination} has an empty address path");
+        }
+        let addr = host_port
+            .to_socket_addrs()?
+            .next()
+            .ok_or(anyhow!("Couldn't resolve OSC destination {host_port}"))?;
+        let local: SocketAddr = if addr.is_ipv6() {
+            "[::]:0".parse()?
+        } else {
+            "0.0.0.0:0".parse()?
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket,
+            addr,
+            path: path.to_owned(),
+            warned: false,
+        })
+    }
+
+    fn send_message(&mut self, name: &str, arg: OscType) {
+        let packet = OscPacket::Message(OscMessage {
+            addr: format!("{path}/{name}", path = self.path),
+            args: vec![arg],
+        });
+        let bytes = match rosc::encoder::encode(&packet) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                warn!("Couldn't encode OSC message: {e}");
+                return;
+            }
+        };
+        match self.socket.send_to(&bytes, self.addr) {
+            Ok(_) => {}
+            // Socket buffer is full. Drop this message.
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+            Err(e) => {
+                if !self.warned {
+                    warn!(
+                        "Couldn't send OSC to {addr}: {e}. Further errors won't be logged.",
+                        addr = self.addr
+                    );
+                    self.warned = true;
+                }
+            }
+        }
+    }
+}
+
+/// Sends `/starplug/apm` (an int) and `/starplug/level` (a float from 0 to 1) whenever APM changes,
+/// to every destination.
+/// Sends are fire-and-forget: if nothing is listening, the messages are dropped.
+pub struct OscSink {
+    destinations: Vec<Destination>,
+}
+
+impl OscSink {
+    /// Send to each `host:port` or `host:port/path`.
+    pub fn new(destinations: &[String]) -> Result<Self> {
+        Ok(Self {
+            destinations: destinations
+                .iter()
+                .map(|destination| Destination::new(destination))
+                .collect::<Result<_>>()?,
+        })
+    }
+}
+
+impl EventSink for OscSink {
+    fn send(&mut self, event: &Event) {
+        if let Event::Apm { apm, level } = event {
+            for destination in &mut self.destinations {
+                destination.send_message("apm", OscType::Int(*apm));
+                destination.send_message("level", OscType::Float(*level as f32));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn listener() -> UdpSocket {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        socket
+    }
+
+    fn receive(socket: &UdpSocket) -> OscMessage {
+        let mut buf = [0u8; 1024];
+        let len = socket.recv(&mut buf).unwrap();
+        match rosc::decoder::decode_udp(&buf[..len]).unwrap().1 {
+            OscPacket::Message(message) => message,
+            packet => panic!("Expected a message, got {packet:?}"),
+        }
+    }
+
+    #[test]
+    fn sends_to_every_destination() {
+        let (first, second) = (listener(), listener());
+        let mut sink = OscSink::new(&[
+            first.local_addr().unwrap().to_string(),
+            format!("{}/avatar/parameters/", second.local_addr().unwrap()),
+        ])
+        .unwrap();
+        sink.send(&Event::Apm {
+            apm: 120,
+            level: 0.5,
+        });
+
+        let apm = receive(&first);
+        assert_eq!(apm.addr, "/starplug/apm");
+        assert_eq!(apm.args, vec![OscType::Int(120)]);
+        assert_eq!(receive(&first).addr, "/starplug/level");
+
+        assert_eq!(receive(&second).addr, "/avatar/parameters/apm");
+        let level = receive(&second);
+        assert_eq!(level.addr, "/avatar/parameters/level");
+        assert_eq!(level.args, vec![OscType::Float(0.5)]);
+    }
+
+    #[test]
+    fn rejects_bad_destinations() {
+        assert!(OscSink::new(&["localhost".to_owned()]).is_err());
+        assert!(OscSink::new(&["127.0.0.1:9000/".to_owned()]).is_err());
+    }
+}
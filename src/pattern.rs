@@ -0,0 +1,159 @@
+//! Test patterns that drive devices without StarCraft, for finding a comfortable range before a game.
+
+use crate::mapper::ApmMapper;
+use crate::{vibrate_all, Args, Output};
+use buttplug::client::ButtplugClient;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tokio::time::sleep;
+use tracing::info;
+
+/// How long a sweep takes to go from 0 to full and back.
+const SWEEP_PERIOD: Duration = Duration::from_secs(10);
+
+/// How often to update the level while playing a pattern.
+const PATTERN_TICK: Duration = Duration::from_millis(50);
+
+/// Level generator for `--pattern`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Pattern {
+    /// Ramp from 0 to full and back.
+    Sweep,
+    /// Alternate between full and off, this many times a second.
+    Pulse { hz: f64 },
+}
+
+impl Pattern {
+    /// Level from 0 to 1, this far into the pattern.
+    fn level_at(self, elapsed: Duration) -> f64 {
+        match self {
+            Pattern::Sweep => {
+                let phase = (elapsed.as_secs_f64() / SWEEP_PERIOD.as_secs_f64()).fract();
+                1.0 - (2.0 * phase - 1.0).abs()
+            }
+            Pattern::Pulse { hz } => {
+                if (elapsed.as_secs_f64() * hz).fract() < 0.5 {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for Pattern {
+    type Err = String;
+
+    /// Parse `sweep` or `pulse:<N>hz`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "sweep" {
+            return Ok(Pattern::Sweep);
+        }
+        let Some(rate) = s.strip_prefix("pulse:") else {
+            return Err(format!(
+                "Unknown pattern {s:?}: expected sweep or pulse:<N>hz"
+            ));
+        };
+        let hz = rate
+            .trim_end_matches("hz")
+            .trim_end_matches("Hz")
+            .parse::<f64>()
+            .map_err(|e| format!("Invalid pulse rate {rate:?}: {e}"))?;
+        if !(hz > 0.0 && hz.is_finite()) {
+            return Err("Pulse rate must be positive!".to_owned());
+        }
+        Ok(Pattern::Pulse { hz })
+    }
+}
+
+/// Play a pattern on every device until cancelled.
+pub async fn play_pattern(
+    args: &Args,
+    clients: &[Arc<Mutex<ButtplugClient>>],
+    output: &Output,
+    pattern: Pattern,
+) {
+    info!("Playing test pattern {pattern:?}. Press Ctrl-C to stop.");
+    let mapper = ApmMapper::new(args);
+    let started = Instant::now();
+    let mut logged_percent = None;
+    loop {
+        let level = pattern.level_at(started.elapsed());
+        // Logging every tick would be too much to read, so log in steps of 10%.
+        let percent = (mapper.cap(level) * 10.0).round() as i32 * 10;
+        if logged_percent != Some(percent) {
+            logged_percent = Some(percent);
+            info!("Test pattern level: {percent}%");
+        }
+        vibrate_all(args, clients, output, level).await;
+        sleep(PATTERN_TICK).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn level_at_secs(pattern: Pattern, secs: f64) -> f64 {
+        pattern.level_at(Duration::from_secs_f64(secs))
+    }
+
+    #[test]
+    fn sweep_ramps_up_and_back_down() {
+        let sweep = Pattern::Sweep;
+        assert!(level_at_secs(sweep, 0.0).abs() < 1e-12);
+        assert!((level_at_secs(sweep, 2.5) - 0.5).abs() < 1e-12);
+        assert!((level_at_secs(sweep, 5.0) - 1.0).abs() < 1e-12);
+        assert!((level_at_secs(sweep, 7.5) - 0.5).abs() < 1e-12);
+        assert!(level_at_secs(sweep, 9.999).abs() < 1e-3);
+    }
+
+    #[test]
+    fn sweep_repeats_every_period() {
+        let sweep = Pattern::Sweep;
+        let period = SWEEP_PERIOD.as_secs_f64();
+        for secs in [0.0, 1.0, 2.5, 5.0, 8.0] {
+            assert!(
+                (level_at_secs(sweep, secs) - level_at_secs(sweep, secs + period)).abs() < 1e-9
+            );
+            assert!(
+                (level_at_secs(sweep, secs) - level_at_secs(sweep, secs + 3.0 * period)).abs()
+                    < 1e-9
+            );
+        }
+    }
+
+    #[test]
+    fn pulse_is_on_for_the_first_half_of_each_cycle() {
+        let pulse = Pattern::Pulse { hz: 2.0 };
+        assert_eq!(level_at_secs(pulse, 0.0), 1.0);
+        assert_eq!(level_at_secs(pulse, 0.2), 1.0);
+        // Each cycle is 0.5 s: on for 0.25 s, then off.
+        assert_eq!(level_at_secs(pulse, 0.25), 0.0);
+        assert_eq!(level_at_secs(pulse, 0.45), 0.0);
+        assert_eq!(level_at_secs(pulse, 0.5), 1.0);
+        assert_eq!(level_at_secs(pulse, 10.3), 0.0);
+    }
+
+    #[test]
+    fn pattern_parsing() {
+        assert_eq!("sweep".parse::<Pattern>(), Ok(Pattern::Sweep));
+        assert_eq!(
+            "pulse:2hz".parse::<Pattern>(),
+            Ok(Pattern::Pulse { hz: 2.0 })
+        );
+        assert_eq!(
+            "pulse:0.5Hz".parse::<Pattern>(),
+            Ok(Pattern::Pulse { hz: 0.5 })
+        );
+        assert_eq!("pulse:3".parse::<Pattern>(), Ok(Pattern::Pulse { hz: 3.0 }));
+        assert!("pulse:0hz".parse::<Pattern>().is_err());
+        assert!("pulse:-1hz".parse::<Pattern>().is_err());
+        assert!("pulse:infhz".parse::<Pattern>().is_err());
+        assert!("pulse:fast".parse::<Pattern>().is_err());
+        assert!("wiggle".parse::<Pattern>().is_err());
+    }
+}
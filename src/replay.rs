@@ -0,0 +1,105 @@
+//! Replay APM recorded by `--log-csv`, for demos and for testing without StarCraft.
+
+use crate::apm_stream::Status;
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, FixedOffset};
+use std::path::Path;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::sleep;
+use tracing::info;
+
+/// Time between samples when replaying as fast as possible.
+/// APM goes through a watch channel, which only keeps the latest value, so this gives the sync loop time to see each one.
+const FASTEST_GAP: Duration = Duration::from_millis(1);
+
+/// Slowest `--replay-speed` other than 0. Anything slower would wait longer than anyone cares to.
+pub const MIN_REPLAY_SPEED: f64 = 0.001;
+
+/// Read the APM rows from a CSV log, skipping the header and marker rows.
+fn read_replay(path: &Path) -> Result<Vec<(DateTime<FixedOffset>, i32)>> {
+    let path_display = path.display();
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Couldn't read replay {path_display}"))?;
+    let mut samples = vec![];
+    for (index, row) in text.lines().enumerate().skip(1) {
+        let line = index + 1;
+        let mut fields = row.split(',');
+        let (Some(timestamp), Some(apm)) = (fields.next(), fields.next()) else {
+            bail!("Line {line} of {path_display} doesn't look like a StarPlug CSV log");
+        };
+        if apm.is_empty() {
+            // Marker row.
+            continue;
+        }
+        let timestamp = DateTime::parse_from_rfc3339(timestamp).map_err(|e| {
+            anyhow!(e).context(format!("Bad timestamp on line {line} of {path_display}"))
+        })?;
+        let apm = apm
+            .parse::<i32>()
+            .map_err(|e| anyhow!(e).context(format!("Bad APM on line {line} of {path_display}")))?;
+        samples.push((timestamp, apm));
+    }
+    if samples.is_empty() {
+        bail!("{path_display} doesn't have any APM in it");
+    }
+    Ok(samples)
+}
+
+/// Start replaying APM at the recorded cadence, sped up by `speed`, or as fast as possible if `speed` is 0.
+/// The channel stays open after the replay finishes, so StarPlug treats it like a game that ended.
+pub fn spawn_replay(path: &Path, speed: f64) -> Result<watch::Receiver<Status>> {
+    let samples = read_replay(path)?;
+    info!(
+        "Replaying {count} APM samples from {path} at {speed}x speed.",
+        count = samples.len(),
+        path = path.display(),
+    );
+
+    let (apm_tx, apm_rx) = watch::channel(Status::Apm(0));
+    tokio::spawn(async move {
+        let mut prev_timestamp = samples[0].0;
+        for (timestamp, apm) in samples {
+            let gap = (timestamp - prev_timestamp).to_std().unwrap_or_default();
+            sleep(replay_gap(gap, speed)).await;
+            prev_timestamp = timestamp;
+            if apm_tx.send(Status::Apm(apm)).is_err() {
+                return;
+            }
+        }
+        info!("Replay finished.");
+        apm_tx.closed().await;
+    });
+    Ok(apm_rx)
+}
+
+/// How long to wait between two samples recorded `gap` apart, when replaying at `speed`.
+/// A gap too long to represent waits forever instead.
+fn replay_gap(gap: Duration, speed: f64) -> Duration {
+    if speed == 0.0 {
+        return FASTEST_GAP;
+    }
+    Duration::try_from_secs_f64(gap.as_secs_f64() / speed).unwrap_or(Duration::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replay_gap_follows_speed() {
+        let gap = Duration::from_secs(2);
+        assert_eq!(replay_gap(gap, 1.0), gap);
+        assert_eq!(replay_gap(gap, 4.0), Duration::from_millis(500));
+        assert_eq!(replay_gap(gap, 0.5), Duration::from_secs(4));
+        assert_eq!(replay_gap(gap, 0.0), FASTEST_GAP);
+    }
+
+    #[test]
+    fn replay_gap_does_not_panic_on_extreme_speeds() {
+        let gap = Duration::from_secs(2);
+        assert_eq!(replay_gap(gap, 1e-300), Duration::MAX);
+        assert_eq!(replay_gap(gap, f64::NAN), Duration::MAX);
+        assert_eq!(replay_gap(gap, f64::INFINITY), Duration::ZERO);
+    }
+}
@@ -0,0 +1,169 @@
+//! StarCraft II support, for `--game sc2`.
+//!
+//! StarCraft II's local client API (`http://localhost:6119/game`) says whether a game is going,
+//! but not anybody's APM, so APM still comes from `--apm-source`.
+//! We use the API to pass APM through only during games, and to say when each game ends.
+
+use crate::apm_stream::Status;
+use anyhow::{anyhow, bail, Result};
+use futures::{select, FutureExt};
+use serde::Deserialize;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tokio::time::{interval, timeout};
+use tracing::{info, warn};
+
+/// Where StarCraft II serves its client API while it's running.
+const CLIENT_API_ADDR: &str = "127.0.0.1:6119";
+
+/// How often to ask StarCraft II whether a game is going.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GameInfo {
+    is_replay: bool,
+    players: Vec<Player>,
+}
+
+#[derive(Deserialize)]
+struct Player {
+    result: String,
+}
+
+impl GameInfo {
+    /// Is somebody playing a game right now?
+    /// Players from a finished game stay listed, with their results, until the next game starts.
+    fn in_game(&self) -> bool {
+        !self.is_replay
+            && self
+                .players
+                .iter()
+                .any(|player| player.result == "Undecided")
+    }
+}
+
+/// Ask StarCraft II's client API about the current game.
+async fn fetch_game_info() -> Result<GameInfo> {
+    let mut stream = TcpStream::connect(CLIENT_API_ADDR).await?;
+    // HTTP/1.0, so the response isn't chunked and ends when the connection closes.
+    stream
+        .write_all(b"GET /game HTTP/1.0\r\nHost: localhost\r\n\r\n")
+        .await?;
+    let mut response = vec![];
+    stream.read_to_end(&mut response).await?;
+    let response = String::from_utf8_lossy(&response);
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| anyhow!("Malformed HTTP response"))?;
+    let status = head.split_whitespace().nth(1).unwrap_or_default();
+    if status != "200" {
+        bail!("HTTP status {status}");
+    }
+    Ok(serde_json::from_str(body)?)
+}
+
+/// Pass status lines through only while a StarCraft II game is going, and send a game over when it ends.
+pub fn gate_on_sc2_games(mut apm_rx: watch::Receiver<Status>) -> watch::Receiver<Status> {
+    let (status_tx, status_rx) = watch::channel(Status::Apm(0));
+    tokio::spawn(async move {
+        let mut in_game = false;
+        let mut warned = false;
+        let mut poll = interval(POLL_INTERVAL);
+        loop {
+            select! {
+                _ = poll.tick().fuse() => {
+                    let now_in_game = match timeout(POLL_INTERVAL, fetch_game_info()).await {
+                        Ok(Ok(game_info)) => {
+                            warned = false;
+                            game_info.in_game()
+                        }
+                        result => {
+                            if !warned {
+                                let e = match result {
+                                    Ok(Err(e)) => e,
+                                    _ => anyhow!("timed out"),
+                                };
+                                warn!("Couldn't reach StarCraft II's client API at {CLIENT_API_ADDR}: {e}. Is StarCraft II running?");
+                                warned = true;
+                            }
+                            false
+                        }
+                    };
+                    if now_in_game == in_game {
+                        continue;
+                    }
+                    in_game = now_in_game;
+                    if in_game {
+                        info!("StarCraft II game started.");
+                    } else {
+                        info!("StarCraft II game ended.");
+                        if status_tx.send(Status::GameOver).is_err() {
+                            return;
+                        }
+                    }
+                }
+                changed = apm_rx.changed().fuse() => {
+                    if changed.is_err() {
+                        return;
+                    }
+                    let status = *apm_rx.borrow_and_update();
+                    if in_game && status_tx.send(status).is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    });
+    status_rx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn game_info(json: &str) -> GameInfo {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn in_game_while_a_game_is_undecided() {
+        let info = game_info(
+            r#"{"isReplay":false,"displayTime":312.5,"players":[
+                {"id":1,"name":"Raynor","type":"user","race":"Terr","result":"Undecided"},
+                {"id":2,"name":"A.I. 1 (Very Easy)","type":"computer","race":"Zerg","result":"Undecided"}
+            ]}"#,
+        );
+        assert!(info.in_game());
+    }
+
+    #[test]
+    fn not_in_game_in_menus() {
+        let info = game_info(r#"{"isReplay":false,"displayTime":0.0,"players":[]}"#);
+        assert!(!info.in_game());
+    }
+
+    #[test]
+    fn not_in_game_after_the_game_is_decided() {
+        let info = game_info(
+            r#"{"isReplay":false,"displayTime":655.0,"players":[
+                {"id":1,"name":"Raynor","type":"user","race":"Terr","result":"Victory"},
+                {"id":2,"name":"A.I. 1 (Very Easy)","type":"computer","race":"Zerg","result":"Defeat"}
+            ]}"#,
+        );
+        assert!(!info.in_game());
+    }
+
+    #[test]
+    fn not_in_game_while_watching_a_replay() {
+        let info = game_info(
+            r#"{"isReplay":true,"displayTime":48.0,"players":[
+                {"id":1,"name":"Raynor","type":"user","race":"Terr","result":"Undecided"},
+                {"id":2,"name":"Kerrigan","type":"user","race":"Zerg","result":"Undecided"}
+            ]}"#,
+        );
+        assert!(!info.in_game());
+    }
+}
@@ -1,9 +1,13 @@
 """
 StarPlug instrumentation script for StarCraft: Remastered on macOS.
-Expects to be told whether StarCraft is already running in the `STARCRAFT_IS_RUNNING` environment variable.
+Expects to be told StarCraft's PID in the `STARCRAFT_PID` environment variable if it's already running,
+or the name of the executable to wait for in `STARCRAFT_PROCESS_NAME` if it isn't.
+Prints `ATTACHED: <pid>` once it's attached, so StarPlug can tell a failed attach from a game that hasn't started,
+and `VERSION: <version>` if it can find StarCraft's version, so StarPlug can say which version it didn't work with.
 """
 
 import os
+import plistlib
 
 import lldb
 
@@ -19,10 +23,13 @@ if starcraft_pid is not None:
     # Assume it's been running long enough to unpack.
     process = target.AttachToProcessWithID(debugger.GetListener(), starcraft_pid, error)
     assert error.success, error.description
+    print('ATTACHED:', process.GetProcessID(), flush=True)
 else:
     # Wait for StarCraft to start.
-    process = target.AttachToProcessWithName(debugger.GetListener(), 'StarCraft', True, error)
+    process_name = os.getenv('STARCRAFT_PROCESS_NAME', 'StarCraft')
+    process = target.AttachToProcessWithName(debugger.GetListener(), process_name, True, error)
     assert error.success, error.description
+    print('ATTACHED:', process.GetProcessID(), flush=True)
 
     # Break on a library function that StarCraft will call once early on,
     # once it's had a chance to start running its own code first.
@@ -35,6 +42,18 @@ else:
 # Find an instruction where we know the APM has recently been calculated.
 # We can only do this once StarCraft proper has started running and unpacked itself.
 executable_module = target.FindModule(target.executable)
+
+# The executable is in `StarCraft.app/Contents/MacOS`, next to the app's `Info.plist`.
+try:
+    info_plist_path = os.path.join(os.path.dirname(target.executable.dirname), 'Info.plist')
+    with open(info_plist_path, 'rb') as info_plist:
+        info = plistlib.load(info_plist)
+    version = info.get('CFBundleShortVersionString') or info.get('CFBundleVersion')
+    if version:
+        print('VERSION:', version, flush=True)
+except (OSError, plistlib.InvalidFileException):
+    pass
+
 code_section = executable_module.FindSection('__text')
 code_start = code_section.addr.GetLoadAddress(target)
 code_bytes = process.ReadMemory(code_start, code_section.size, error)
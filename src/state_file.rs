@@ -0,0 +1,70 @@
+//! Keep a small JSON file up to date with StarPlug's current state, for overlays and other tools.
+
+use crate::events::{Events, State};
+use anyhow::Result;
+use chrono::Utc;
+use futures::future::pending;
+use futures::{select, FutureExt};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::interval;
+use tracing::warn;
+
+/// Replace the file in one step, so readers never see a partly written file.
+pub fn write_atomically(path: &Path, contents: &[u8]) -> Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut temp = tempfile::NamedTempFile::new_in(dir)?;
+    temp.write_all(contents)?;
+    temp.persist(path)?;
+    Ok(())
+}
+
+/// Write the state file after every event, or every `interval` if given.
+pub fn spawn_state_file(events: &Events, path: PathBuf, every: Option<Duration>) {
+    let mut rx = events.subscribe();
+    let started = Instant::now();
+    tokio::spawn(async move {
+        let mut state = State::default();
+        let mut ticker = every.map(interval);
+        loop {
+            let tick = async {
+                match ticker.as_mut() {
+                    Some(ticker) => {
+                        ticker.tick().await;
+                    }
+                    None => pending::<()>().await,
+                }
+            };
+            select! {
+                event = rx.recv().fuse() => match event {
+                    Ok(event) => {
+                        state.apply(&event);
+                        if ticker.is_some() {
+                            continue;
+                        }
+                    }
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                },
+                _ = tick.fuse() => {}
+            }
+
+            state.uptime_secs = started.elapsed().as_secs_f64();
+            state.updated = Some(Utc::now());
+            let result = serde_json::to_vec_pretty(&state)
+                .map_err(anyhow::Error::from)
+                .and_then(|json| write_atomically(&path, &json));
+            if let Err(e) = result {
+                warn!(
+                    "Couldn't write state file {path}: {e}",
+                    path = path.display()
+                );
+            }
+        }
+    });
+}
@@ -0,0 +1,286 @@
+//! Full-screen dashboard showing APM, vibration level, and connections, for reading mid-game.
+
+use crate::events::{Events, State};
+use crate::mapper::ApmMapper;
+use crate::{emergency_stop, release_emergency_stop, toggle_pause, Args, Clients, Output};
+use anyhow::Result;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Gauge, List, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+use std::collections::VecDeque;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::watch;
+
+/// How often to redraw, and how long to wait for a key press between redraws.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Number of log lines kept for the log pane.
+const LOG_LINES: usize = 200;
+
+/// Log lines written while the dashboard is showing.
+static LOG: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+
+/// Whether logs go to the log pane instead of stderr.
+static LOG_TO_PANE: AtomicBool = AtomicBool::new(false);
+
+/// Log writer for `tracing_subscriber` that keeps logs from drawing over the dashboard.
+/// Writes to stderr when the dashboard isn't showing.
+#[derive(Default)]
+pub struct LogWriter {
+    buffer: Vec<u8>,
+}
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if LOG_TO_PANE.load(Ordering::Relaxed) {
+            self.buffer.extend_from_slice(buf);
+            Ok(buf.len())
+        } else {
+            std::io::stderr().write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if self.buffer.is_empty() {
+            return std::io::stderr().flush();
+        }
+        let text = String::from_utf8_lossy(&self.buffer).into_owned();
+        self.buffer.clear();
+        let mut log = LOG.lock().unwrap();
+        for line in text.lines() {
+            if log.len() == LOG_LINES {
+                log.pop_front();
+            }
+            log.push_back(line.to_owned());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for LogWriter {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+/// Handle to the dashboard. Dropping it puts the terminal back the way it was.
+pub struct Tui {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+    updater: tokio::task::JoinHandle<()>,
+    quit_rx: watch::Receiver<bool>,
+}
+
+impl Tui {
+    /// Finishes when the user presses q or Ctrl-C in the dashboard.
+    pub fn quit_requested(&self) -> impl std::future::Future<Output = ()> {
+        let mut quit_rx = self.quit_rx.clone();
+        async move {
+            while !*quit_rx.borrow_and_update() {
+                if quit_rx.changed().await.is_err() {
+                    // The dashboard failed, so nobody can ask to quit from it anymore.
+                    return futures::future::pending().await;
+                }
+            }
+        }
+    }
+
+    /// Stop showing the dashboard.
+    pub fn close(self) {}
+}
+
+impl Drop for Tui {
+    fn drop(&mut self) {
+        self.updater.abort();
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        LOG_TO_PANE.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Take over the terminal and start showing the dashboard.
+pub fn spawn_tui(
+    args: Arc<Args>,
+    events: &Events,
+    clients: Clients,
+    output: Output,
+) -> Result<Tui> {
+    let state = Arc::new(Mutex::new(State::default()));
+
+    let mut rx = events.subscribe();
+    let updater_state = state.clone();
+    let updater = tokio::spawn(async move {
+        loop {
+            match rx.recv().await {
+                Ok(event) => updater_state.lock().unwrap().apply(&event),
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+
+    let terminal = ratatui::try_init()?;
+    LOG_TO_PANE.store(true, Ordering::Relaxed);
+    let stop = Arc::new(AtomicBool::new(false));
+    let (quit_tx, quit_rx) = watch::channel(false);
+    let thread_stop = stop.clone();
+    let runtime = tokio::runtime::Handle::current();
+    // Drawing and reading keys both block, so they get their own thread.
+    let thread = std::thread::spawn(move || {
+        let keys = Keys {
+            args: args.clone(),
+            runtime,
+            clients,
+            quit_tx,
+        };
+        if let Err(e) = run(terminal, &args, &state, &output, &thread_stop, &keys) {
+            LOG_TO_PANE.store(false, Ordering::Relaxed);
+            ratatui::restore();
+            tracing::error!("Dashboard stopped: {e}");
+            return;
+        }
+        ratatui::restore();
+    });
+
+    Ok(Tui {
+        stop,
+        thread: Some(thread),
+        updater,
+        quit_rx,
+    })
+}
+
+/// What the dashboard's keys need to do their jobs.
+struct Keys {
+    args: Arc<Args>,
+    runtime: tokio::runtime::Handle,
+    clients: Clients,
+    quit_tx: watch::Sender<bool>,
+}
+
+fn run(
+    mut terminal: DefaultTerminal,
+    args: &Args,
+    state: &Mutex<State>,
+    output: &Output,
+    stop: &AtomicBool,
+    keys: &Keys,
+) -> std::io::Result<()> {
+    while !stop.load(Ordering::Relaxed) {
+        terminal.draw(|frame| draw(frame, args, &state.lock().unwrap(), output))?;
+        if !event::poll(REDRAW_INTERVAL)? {
+            continue;
+        }
+        if let Event::Key(key) = event::read()? {
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            let ctrl_c =
+                key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+            match key.code {
+                _ if key.code == KeyCode::Char('q') || ctrl_c => {
+                    keys.quit_tx.send_replace(true);
+                }
+                KeyCode::Char('p') | KeyCode::Char(' ') => {
+                    let (args, clients, output) =
+                        (keys.args.clone(), keys.clients.clone(), output.clone());
+                    keys.runtime.spawn(async move {
+                        toggle_pause(&args, &clients, &output, "Press P to resume vibration.")
+                            .await;
+                    });
+                }
+                KeyCode::Char('s') => {
+                    keys.runtime
+                        .spawn(emergency_stop(keys.clients.clone(), output.clone()));
+                }
+                // Capital U, so it takes a deliberate Shift to undo an emergency stop.
+                KeyCode::Char('U') => release_emergency_stop(output),
+                _ => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+fn draw(frame: &mut Frame, args: &Args, state: &State, output: &Output) {
+    let devices: Vec<&String> = state.driven_devices().map(|device| &device.name).collect();
+    let [status_area, level_area, devices_area, log_area] = Layout::vertical([
+        Constraint::Length(5),
+        Constraint::Length(3),
+        Constraint::Length(devices.len().max(1) as u16 + 2),
+        Constraint::Min(3),
+    ])
+    .areas(frame.area());
+
+    let connected = |yes: bool| if yes { "connected" } else { "not connected" };
+    let status = Paragraph::new(vec![
+        Line::from(format!(
+            "APM:       {rate}",
+            rate = args.display_units.format(state.apm as f64)
+        )),
+        Line::from(format!("Intiface:  {}", connected(state.connected))),
+        Line::from(format!("StarCraft: {}", connected(state.game_running))),
+    ])
+    .block(Block::bordered().title("StarPlug (q to quit, p to pause, s for emergency stop)"));
+    frame.render_widget(status, status_area);
+
+    let level = output.get().clamp(0.0, 1.0);
+    let paused = if output.is_emergency_stopped() {
+        " (EMERGENCY STOP: press U to unlock)"
+    } else if output.is_paused() {
+        " (paused)"
+    } else {
+        ""
+    };
+    let gauge = Gauge::default()
+        .block(Block::bordered().title("Intensity"))
+        .ratio(level)
+        .label(format!("{percent:.0}%{paused}", percent = level * 100.0));
+    frame.render_widget(gauge, level_area);
+
+    let mapper = ApmMapper::new(args);
+    let device_lines: Vec<String> = if devices.is_empty() {
+        vec!["No devices connected.".to_owned()]
+    } else {
+        devices
+            .iter()
+            .map(|name| {
+                let device_level =
+                    args.device_level(&mapper, name, devices.len(), level, output.apm());
+                let battery = match state.batteries.get(*name) {
+                    Some(battery) => format!(" (battery {percent:.0}%)", percent = battery * 100.0),
+                    None => String::new(),
+                };
+                format!(
+                    "{name}: {percent:.0}%{battery}",
+                    percent = device_level * 100.0
+                )
+            })
+            .collect()
+    };
+    frame.render_widget(
+        List::new(device_lines).block(Block::bordered().title("Devices")),
+        devices_area,
+    );
+
+    let log = LOG.lock().unwrap();
+    let visible = log_area.height.saturating_sub(2) as usize;
+    let log_lines: Vec<Line> = log
+        .iter()
+        .skip(log.len().saturating_sub(visible))
+        .map(|line| Line::from(line.as_str()))
+        .collect();
+    frame.render_widget(
+        Paragraph::new(log_lines).block(Block::bordered().title("Log")),
+        log_area,
+    );
+}